@@ -2,10 +2,10 @@ use redis::{self, Commands};
 
 
 fn main(){
-    let mut r_client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+    let r_client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
     let mut r_con = r_client.get_connection().unwrap();
 
-    for i in 1..100000 {
+    for _ in 1..100000 {
         vec_guy(&mut r_con);
         u8_guy(&mut r_con);
     }
@@ -13,9 +13,10 @@ fn main(){
 
 fn vec_guy(c: &mut redis::Connection) {
     let gg: Vec<u8> = vec![0x20, 0x22, 0x33];
-    let d1: redis::Value = c.zadd("XD", gg, 100).unwrap();
+    let _d1: redis::Value = c.zadd("XD", gg, 100).unwrap();
 }
 
+#[allow(dead_code)]
 struct InfoHash([u8; 40]);
 
 struct RawVal<const T: usize>([u8; T]);
@@ -35,6 +36,6 @@ impl<const T: usize> redis::ToRedisArgs for RawVal<T> {
 fn u8_guy(c: &mut redis::Connection) {
     // let wp: [u8; 3] = [0x20, 0x22, 0x33];
     let wp: [u8; 40] = [0; 40];
-    let WP = RawVal(wp);
-    let d2: redis::Value = c.zadd("XD", WP, 100).unwrap();
+    let raw_wp = RawVal(wp);
+    let _d2: redis::Value = c.zadd("XD", raw_wp, 100).unwrap();
 }
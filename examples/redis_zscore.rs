@@ -25,10 +25,7 @@ fn main(){
     };
 
     // We work with lower level value, since we only need to detect nil or non-nil. This can save us from type checking (I think?)
-    let exist_lowlevel = match r_client.zscore::<_, _, redis::Value>("thezset", "KEY2").unwrap() {
-        redis::Value::Nil => false,
-        _ => true,
-    };
+    let exist_lowlevel = !matches!(r_client.zscore::<_, _, redis::Value>("thezset", "KEY2").unwrap(), redis::Value::Nil);
 
     println!("exist_highlevel is {:?}, exist_lowlevel is {:?}", exist_highlevel, exist_lowlevel);
     
@@ -1,5 +1,6 @@
 use redis::{self, Commands};
 
+#[allow(dead_code)]
 #[derive(Debug)]
 enum Exists {
     Yes,
@@ -29,9 +30,7 @@ fn main(){
 
     for element in &zrange_result {
         if let redis::Value::Data(xd) = element {
-            for i in 0..6 {
-                seeders[pos + i] = xd[i];
-            }
+            seeders[pos..pos + 6].copy_from_slice(&xd[..6]);
             pos += 6;
         }
 
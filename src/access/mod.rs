@@ -0,0 +1,224 @@
+// Which torrents kiryuu tracks. Hashes on the denylist (e.g. after a DMCA notice) are refused,
+// and if there's an allowlist, only the hashes on it are ("registered torrents only").
+// Each list comes from a file (a hex infohash per line, `#` for comments) and/or a redis set
+// of hex infohashes, and is re-read every `--list-refresh-interval`: the file when its mtime
+// changed, the set every time.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_web::web;
+
+use crate::byte_functions::types::RawVal;
+use crate::store::SwarmStore;
+use crate::AppState;
+
+type Hashes = HashSet<RawVal<40>>;
+
+/// The lowercase hex form we key torrents by, see `byte_functions::url_encoded_to_hex_u8`
+fn parse_hash(hex: &[u8]) -> Option<RawVal<40>> {
+    let hex: [u8; 40] = hex.try_into().ok()?;
+    if !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    return Some(RawVal(hex.map(|c| c.to_ascii_lowercase())));
+}
+
+fn parse_file(contents: &str) -> Result<Hashes, String> {
+    let mut hashes = HashSet::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse_hash(line.as_bytes()) {
+            Some(info_hash) => hashes.insert(info_hash),
+            None => return Err(format!("line {} isn't a hex infohash", number + 1)),
+        };
+    }
+
+    return Ok(hashes);
+}
+
+/// Where one list comes from
+pub struct List {
+    file: Option<PathBuf>,
+    redis_set: Option<String>,
+    /// The file's mtime & hashes as of the last read
+    file_read: Mutex<Option<(SystemTime, Hashes)>>,
+}
+
+impl List {
+    pub fn new(file: Option<PathBuf>, redis_set: Option<String>) -> List {
+        return List { file, redis_set, file_read: Mutex::new(None) };
+    }
+
+    fn is_set_up(&self) -> bool {
+        return self.file.is_some() || self.redis_set.is_some();
+    }
+
+    fn read_file(&self, path: &PathBuf) -> Result<Hashes, String> {
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let mut file_read = self.file_read.lock().expect("poisoned list");
+
+        if let Some((read_at, hashes)) = file_read.as_ref() {
+            if *read_at == modified {
+                return Ok(hashes.clone());
+            }
+        }
+
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let hashes = parse_file(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        *file_read = Some((modified, hashes.clone()));
+
+        return Ok(hashes);
+    }
+
+    async fn load(&self, store: &dyn SwarmStore) -> Result<Hashes, String> {
+        let mut hashes = match &self.file {
+            Some(path) => self.read_file(path)?,
+            None => HashSet::new(),
+        };
+
+        if let Some(key) = &self.redis_set {
+            let members = store.fetch_set(key).await.map_err(|e| format!("Can't read {}: {}", key, e))?;
+            let mut skipped = 0;
+
+            for member in members {
+                match parse_hash(&member) {
+                    Some(info_hash) => {
+                        hashes.insert(info_hash);
+                    },
+                    None => skipped += 1,
+                }
+            }
+
+            if skipped > 0 {
+                println!("Skipped {} members of {} that aren't hex infohashes", skipped, key);
+            }
+        }
+
+        return Ok(hashes);
+    }
+}
+
+struct Lists {
+    /// None if there's no allowlist, i.e. every torrent not denied is tracked
+    allow: Option<Hashes>,
+    deny: Hashes,
+}
+
+pub struct AccessLists {
+    allowlist: List,
+    denylist: List,
+    lists: RwLock<Arc<Lists>>,
+}
+
+impl AccessLists {
+    /// Lets everything through until the first `load`
+    pub fn new(allowlist: List, denylist: List) -> AccessLists {
+        return AccessLists { allowlist, denylist, lists: RwLock::new(Arc::new(Lists { allow: None, deny: HashSet::new() })) };
+    }
+
+    /// The failure reason for announces & scrapes of `info_hash`, if it isn't tracked
+    pub fn check(&self, info_hash: &RawVal<40>) -> Result<(), &'static str> {
+        let lists = self.lists.read().expect("poisoned lists");
+
+        if lists.deny.contains(info_hash) {
+            return Err("Torrent is not allowed on this tracker");
+        }
+        if let Some(allow) = &lists.allow {
+            if !allow.contains(info_hash) {
+                return Err("Unregistered torrent");
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// (Re)read both lists, keeping the current ones if either can't be read
+    pub async fn load(&self, store: &dyn SwarmStore) -> Result<(), String> {
+        let allow = match self.allowlist.is_set_up() {
+            true => Some(self.allowlist.load(store).await?),
+            false => None,
+        };
+        let deny = self.denylist.load(store).await?;
+
+        *self.lists.write().expect("poisoned lists") = Arc::new(Lists { allow, deny });
+        return Ok(());
+    }
+}
+
+/// Reload forever, every `interval`
+pub async fn run(data: web::Data<AppState>, interval: Duration) {
+    let mut interval = actix_web::rt::time::interval(interval);
+    // The first tick is right away, and `main` already loaded them
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = data.access.load(data.store.as_ref()).await {
+            println!("Err reloading torrent lists, keeping the current ones: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const INFO_HASH: RawVal<40> = RawVal(*b"41aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+    const OTHER_HASH: RawVal<40> = RawVal(*b"42aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+
+    fn list_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kiryuu-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        return path;
+    }
+
+    #[test]
+    fn parses_files() {
+        let hashes = parse_file("# DMCA 123\n41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n\n  42aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa # a comment\n").unwrap();
+        assert_eq!(HashSet::from([INFO_HASH, OTHER_HASH]), hashes);
+
+        assert_eq!(Err("line 2 isn't a hex infohash".to_string()), parse_file("# ok\n41aaaa\n"));
+        assert!(parse_file("41aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaz").is_err());
+    }
+
+    #[actix_web::test]
+    async fn checks_lists() {
+        let store = MemoryStore::new();
+
+        let open = AccessLists::new(List::new(None, None), List::new(None, None));
+        open.load(&store).await.unwrap();
+        assert!(open.check(&INFO_HASH).is_ok());
+
+        let denylist = list_file("deny", "41aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n");
+        let denied = AccessLists::new(List::new(None, None), List::new(Some(denylist.clone()), None));
+        denied.load(&store).await.unwrap();
+        assert_eq!(Err("Torrent is not allowed on this tracker"), denied.check(&INFO_HASH));
+        assert!(denied.check(&OTHER_HASH).is_ok());
+
+        let allowlist = list_file("allow", "42aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n");
+        let allowed = AccessLists::new(List::new(Some(allowlist.clone()), None), List::new(None, None));
+        allowed.load(&store).await.unwrap();
+        assert_eq!(Err("Unregistered torrent"), allowed.check(&INFO_HASH));
+        assert!(allowed.check(&OTHER_HASH).is_ok());
+
+        // A broken file keeps the lists as they were
+        std::fs::write(&allowlist, "nope\n").unwrap();
+        std::fs::File::options().write(true).open(&allowlist).unwrap().set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        assert!(allowed.load(&store).await.is_err());
+        assert!(allowed.check(&OTHER_HASH).is_ok());
+
+        std::fs::remove_file(denylist).unwrap();
+        std::fs::remove_file(allowlist).unwrap();
+    }
+}
@@ -1,17 +1,92 @@
 pub mod types;
 
-pub fn make_redis_keys(info_hash: &types::RawVal<40>) -> (types::RawVal<48>, types::RawVal<49>, types::RawVal<46>) {
-    let mut seeder_key: [u8; 48] = *b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA_seeders";
-    let mut leecher_key: [u8; 49] = *b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA_leechers";
-    let mut cache_key: [u8; 46] = *b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA_cache";
+// All of a torrent's keys start with `{<infohash>}`. Redis Cluster only hashes
+// what's in the braces (a "hash tag"), so they all land in the same slot,
+// and the scripts / pipelines for a torrent can use them together.
 
-    for i in 0..40 {
-        seeder_key[i] = info_hash[i];
-        leecher_key[i] = info_hash[i];
-        cache_key[i] = info_hash[i]
-    }
+// The cached reply depends on how many peers were asked for, so `numwant` (at most 999)
+// is part of the cache key
+pub fn make_redis_keys(info_hash: &types::RawVal<40>, numwant: u16) -> (types::RawVal<50>, types::RawVal<51>, types::RawVal<52>) {
+    let mut seeder_key: [u8; 50] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_seeders";
+    let mut leecher_key: [u8; 51] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_leechers";
+
+    seeder_key[1..41].copy_from_slice(&info_hash.0);
+    leecher_key[1..41].copy_from_slice(&info_hash.0);
+
+    return (types::RawVal(seeder_key), types::RawVal(leecher_key), make_cache_key(info_hash, numwant));
+}
+
+pub fn make_cache_key(info_hash: &types::RawVal<40>, numwant: u16) -> types::RawVal<52> {
+    let mut cache_key: [u8; 52] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_cache_000";
+
+    cache_key[1..41].copy_from_slice(&info_hash.0);
+    cache_key[49] = nibble_to_ascii((numwant / 100 % 10) as u8);
+    cache_key[50] = nibble_to_ascii((numwant / 10 % 10) as u8);
+    cache_key[51] = nibble_to_ascii((numwant % 10) as u8);
+
+    return types::RawVal(cache_key);
+}
+
+// IPv6 peers live in their own sets, since their compact form is 18 bytes instead of 6
+pub fn make_redis_keys6(info_hash: &types::RawVal<40>) -> (types::RawVal<51>, types::RawVal<52>) {
+    let mut seeder_key: [u8; 51] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_seeders6";
+    let mut leecher_key: [u8; 52] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_leechers6";
+
+    seeder_key[1..41].copy_from_slice(&info_hash.0);
+    leecher_key[1..41].copy_from_slice(&info_hash.0);
+
+    return (types::RawVal(seeder_key), types::RawVal(leecher_key));
+}
+
+// BEP 21 partial seeds, (IPv4, IPv6)
+pub fn make_partial_seeds_keys(info_hash: &types::RawVal<40>) -> (types::RawVal<56>, types::RawVal<57>) {
+    let mut partial_key: [u8; 56] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_partial_seeds";
+    let mut partial_key6: [u8; 57] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_partial_seeds6";
+
+    partial_key[1..41].copy_from_slice(&info_hash.0);
+    partial_key6[1..41].copy_from_slice(&info_hash.0);
+
+    return (types::RawVal(partial_key), types::RawVal(partial_key6));
+}
+
+// The IPv6 endpoints that are dual stack peers' mirrors, see `swarm::PeerRole::is_mirror`
+pub fn make_mirrors_key(info_hash: &types::RawVal<40>) -> types::RawVal<51> {
+    let mut mirrors_key: [u8; 51] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_mirrors6";
+
+    mirrors_key[1..41].copy_from_slice(&info_hash.0);
+
+    return types::RawVal(mirrors_key);
+}
+
+// The torrent's stats hash (seeders, leechers, downloaded)
+pub fn make_stats_key(info_hash: &types::RawVal<40>) -> types::RawVal<42> {
+    let mut stats_key: [u8; 42] = *b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}";
+
+    stats_key[1..41].copy_from_slice(&info_hash.0);
+
+    return types::RawVal(stats_key);
+}
+
+// When the peer (its compact `ip_port`) may announce the torrent again, see `rate_limit`
+pub fn make_throttle_key(info_hash: &types::RawVal<40>, ip_port: &[u8]) -> Vec<u8> {
+    let mut throttle_key = b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_throttle_".to_vec();
 
-    return (types::RawVal(seeder_key), types::RawVal(leecher_key), types::RawVal(cache_key));
+    throttle_key[1..41].copy_from_slice(&info_hash.0);
+    throttle_key.extend_from_slice(ip_port);
+
+    return throttle_key;
+}
+
+// The peer's last transfer report in private mode, see `private`
+pub fn make_report_key(info_hash: &types::RawVal<40>, user: &str, ip_port: &[u8]) -> Vec<u8> {
+    let mut report_key = b"{AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_report_".to_vec();
+
+    report_key[1..41].copy_from_slice(&info_hash.0);
+    report_key.extend_from_slice(user.as_bytes());
+    report_key.push(b'_');
+    report_key.extend_from_slice(ip_port);
+
+    return report_key;
 }
 
 pub fn url_encoded_to_hex_u8(urlenc: &str) -> [u8; 40] {
@@ -65,6 +140,69 @@ pub fn url_encoded_to_hex_u8(urlenc: &str) -> [u8; 40] {
     return hex_str_bytes;
 }
 
+// Same as `url_encoded_to_hex_u8`, but first check that `urlenc` really is
+// 20 bytes once decoded, and that every % is followed by two hex digits. Otherwise we'd
+// either index out of bounds (too long, or a truncated %XX at the end), silently pad it
+// with "A"s (too short), or pass on junk that isn't hex (e.g. "%!!")
+pub fn url_encoded_to_hex_u8_checked(urlenc: &str) -> Option<[u8; 40]> {
+    let raw = urlenc.as_bytes();
+    let mut pos_urlenc = 0;
+    let mut decoded_len = 0;
+
+    while pos_urlenc < raw.len() {
+        if raw[pos_urlenc] == 0x25 {
+            match raw.get(pos_urlenc + 1..pos_urlenc + 3) {
+                Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => pos_urlenc += 3,
+                _ => return None,
+            }
+        } else {
+            pos_urlenc += 1;
+        }
+        decoded_len += 1;
+    }
+
+    if decoded_len != 20 {
+        return None;
+    }
+
+    return Some(url_encoded_to_hex_u8(urlenc));
+}
+
+// Convert a raw 20 byte infohash (e.g. from a UDP packet) to the
+// same lowercase hex representation `url_encoded_to_hex_u8` gives us
+pub fn raw_to_hex_u8(raw: &[u8; 20]) -> [u8; 40] {
+    let mut hex_str_bytes: [u8; 40] = [0; 40];
+
+    for (i, byte) in raw.iter().enumerate() {
+        hex_str_bytes[i*2] = nibble_to_ascii((0b11110000 & byte) >> 4);
+        hex_str_bytes[i*2+1] = nibble_to_ascii(0b00001111 & byte);
+    }
+
+    return hex_str_bytes;
+}
+
+// The reverse of `raw_to_hex_u8`, for when we need to give the client back
+// the raw infohash (e.g. as the key in a scrape reply)
+pub fn hex_to_raw_u8(hex: &[u8; 40]) -> [u8; 20] {
+    let mut raw: [u8; 20] = [0; 20];
+
+    for (i, byte) in raw.iter_mut().enumerate() {
+        *byte = (ascii_to_nibble(hex[i*2]) << 4) | ascii_to_nibble(hex[i*2+1]);
+    }
+
+    return raw;
+}
+
+// Inverse of `nibble_to_ascii`. Only needs to handle what we generate, i.e. 0-9 & a-f
+#[inline(always)]
+fn ascii_to_nibble(ascii: u8) -> u8 {
+    if ascii < 0x3A {
+        ascii - 0x30
+    } else {
+        ascii - 0x57
+    }
+}
+
 // Based on some PoC, seems fastest way to convert
 // A nibble to it's ascii
 // https://godbolt.org/z/bcr46c7ha
@@ -82,10 +220,8 @@ pub fn ip_str_port_u16_to_bytes(ip_addr: &std::net::Ipv4Addr, port: u16) -> [u8;
     let mut result: [u8; 6] = [0; 6];
     let ip_octets = ip_addr.octets();
 
-    for i in 0..4 {
-        result[i] = ip_octets[i];
-    }
-    
+    result[..4].copy_from_slice(&ip_octets);
+
     let portu8 = port.to_be_bytes();
     result[4] = portu8[0];
     result[5] = portu8[1];
@@ -93,6 +229,16 @@ pub fn ip_str_port_u16_to_bytes(ip_addr: &std::net::Ipv4Addr, port: u16) -> [u8;
     return result;
 }
 
+// Convert the ipv6 addr, port combo to a [u8; 18] (BEP 7 compact form)
+pub fn ip6_port_u16_to_bytes(ip_addr: &std::net::Ipv6Addr, port: u16) -> [u8; 18] {
+    let mut result: [u8; 18] = [0; 18];
+
+    result[..16].copy_from_slice(&ip_addr.octets());
+    result[16..].copy_from_slice(&port.to_be_bytes());
+
+    return result;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,6 +265,31 @@ mod tests {
         // e.g. "%A" <- only 1 char following percent
     }
 
+    #[test]
+    fn raw_matches_url_encoded() {
+        let raw: [u8; 20] = [0xdd, 0x00, 0xd2, 0x1c, 0x75, 0x44, 0x41, 0xaa, 0x4c, 0xb6, 0x4a, 0x1e, 0xa7, 0x7a, 0x2c, 0x76, 0x46, 0x41, 0x52, 0xc3];
+        assert_eq!(*b"dd00d21c754441aa4cb64a1ea77a2c76464152c3", raw_to_hex_u8(&raw));
+        assert_eq!(url_encoded_to_hex_u8("%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3"), raw_to_hex_u8(&raw));
+    }
+
+    #[test]
+    fn checks_infohash_length() {
+        assert_eq!(Some(url_encoded_to_hex_u8("%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3")), url_encoded_to_hex_u8_checked("%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3"));
+        assert_eq!(None, url_encoded_to_hex_u8_checked("%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR")); // 19 bytes
+        assert_eq!(None, url_encoded_to_hex_u8_checked("%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3A")); // 21 bytes
+        assert_eq!(None, url_encoded_to_hex_u8_checked("%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFARA%C")); // Truncated %XX
+        assert_eq!(None, url_encoded_to_hex_u8_checked("%!!%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3")); // Not hex
+        assert_eq!(None, url_encoded_to_hex_u8_checked("%D%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3A"));
+        assert_eq!(None, url_encoded_to_hex_u8_checked(""));
+    }
+
+    #[test]
+    fn hex_roundtrips_to_raw() {
+        let raw: [u8; 20] = [0xdd, 0x00, 0xd2, 0x1c, 0x75, 0x44, 0x41, 0xaa, 0x4c, 0xb6, 0x4a, 0x1e, 0xa7, 0x7a, 0x2c, 0x76, 0x46, 0x41, 0x52, 0xc3];
+        assert_eq!(raw, hex_to_raw_u8(&raw_to_hex_u8(&raw)));
+        assert_eq!(raw, hex_to_raw_u8(b"dd00d21c754441aa4cb64a1ea77a2c76464152c3"));
+    }
+
     #[test]
     fn can_parse_ip_port() {
         assert_eq!(
@@ -134,4 +305,60 @@ mod tests {
             ip_str_port_u16_to_bytes(&std::net::Ipv4Addr::new(192, 168, 1, 1), 27017)
         );
     }
+
+    #[test]
+    fn can_parse_ip6_port() {
+        assert_eq!(
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 13, 5],
+            ip6_port_u16_to_bytes(&std::net::Ipv6Addr::LOCALHOST, 3333)
+        );
+        assert_eq!(
+            vec![0x26, 0x06, 0x47, 0x00, 0x47, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x11, 0x11, 255, 255],
+            ip6_port_u16_to_bytes(&"2606:4700:4700::1111".parse().unwrap(), 65535)
+        );
+    }
+
+    #[test]
+    fn makes_redis_keys() {
+        let (seeders, leechers, cache) = make_redis_keys(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), 50);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_seeders", seeders.0);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_leechers", leechers.0);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_cache_050", cache.0);
+
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_cache_000", make_cache_key(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), 0).0);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_cache_999", make_cache_key(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), 999).0);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}", make_stats_key(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA")).0);
+    }
+
+    #[test]
+    fn makes_redis_keys6() {
+        let (seeders6, leechers6) = make_redis_keys6(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"));
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_seeders6", seeders6.0);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_leechers6", leechers6.0);
+    }
+
+    #[test]
+    fn makes_partial_seeds_keys() {
+        let (partial_seeds, partial_seeds6) = make_partial_seeds_keys(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"));
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_partial_seeds", partial_seeds.0);
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_partial_seeds6", partial_seeds6.0);
+    }
+
+    #[test]
+    fn makes_mirrors_key() {
+        let mirrors = make_mirrors_key(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"));
+        assert_eq!(*b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_mirrors6", mirrors.0);
+    }
+
+    #[test]
+    fn makes_report_key() {
+        let key = make_report_key(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), "42", b"\x7f\0\0\x01\x1a\xe1");
+        assert_eq!(b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_report_42_\x7f\0\0\x01\x1a\xe1".to_vec(), key);
+    }
+
+    #[test]
+    fn makes_throttle_key() {
+        let key = make_throttle_key(&types::RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), b"\x7f\0\0\x01\x1a\xe1");
+        assert_eq!(b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_throttle_\x7f\0\0\x01\x1a\xe1".to_vec(), key);
+    }
 }
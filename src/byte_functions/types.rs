@@ -1,6 +1,7 @@
 // Define a struct to wrap [u8; _] values
 // So we can implement redis::ToRedisArgs on them
 // directly (i.e. binary redis arg)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct RawVal<const T: usize>(pub [u8; T]);
 
 impl<const T: usize> redis::ToRedisArgs for RawVal<T> {
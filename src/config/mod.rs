@@ -0,0 +1,585 @@
+// kiryuu's settings. Each one comes from (first wins) its CLI flag, its `KIRYUU_*`
+// environment variable, the `--config` TOML file, or its default.
+// The file uses the flags' names, in snake_case, see `kiryuu.example.toml`.
+// On SIGHUP the file is read again, and the settings that can change while running
+// (see `Settings`) are swapped in.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+use crate::{peer_selection, rate_limit, store, swarm, AppState};
+
+/// Simple
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML file to read settings from. Flags & environment variables override it
+    #[arg(long, env = "KIRYUU_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the settings, print the effective ones and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Port for tracker to listen on. Default: 6969
+    #[arg(long, env = "KIRYUU_PORT")]
+    pub port: Option<u16>,
+
+    /// IP to bind tracker to. Default: 0.0.0.0
+    #[arg(long, env = "KIRYUU_HOST")]
+    pub host: Option<String>,
+
+    /// Where to keep the swarms. Default: redis
+    #[arg(long, value_enum, env = "KIRYUU_STORE")]
+    pub store: Option<store::Backend>,
+
+    /// Address of redis instance. Repeat (or comma separate) to shard torrents across several. Default: 127.0.0.1:6379
+    #[arg(long, value_delimiter = ',', env = "KIRYUU_REDIS_HOST")]
+    pub redis_host: Vec<String>,
+
+    /// Treat the --redis-host(s) as seed nodes of a Redis Cluster
+    #[arg(long, env = "KIRYUU_REDIS_CLUSTER")]
+    pub redis_cluster: bool,
+
+    /// Most peers to hand out in an announce reply, 1-999. Default: 200
+    #[arg(long, env = "KIRYUU_MAX_NUMWANT")]
+    pub max_numwant: Option<u16>,
+
+    /// Peers to hand out when the client doesn't send `numwant`, at most --max-numwant. Default: 50
+    #[arg(long, env = "KIRYUU_DEFAULT_NUMWANT")]
+    pub default_numwant: Option<u16>,
+
+    /// How to pick the peers handed out in an announce reply. Default: random
+    #[arg(long, value_enum, env = "KIRYUU_PEER_SELECTION")]
+    pub peer_selection: Option<peer_selection::Strategy>,
+
+    /// Port for the UDP (BEP 15) tracker to listen on. Default: same as --port
+    #[arg(long, env = "KIRYUU_UDP_PORT")]
+    pub udp_port: Option<u16>,
+
+    /// Only serve the HTTP tracker
+    #[arg(long, env = "KIRYUU_DISABLE_UDP")]
+    pub disable_udp: bool,
+
+    /// Seconds clients are told to wait between announces. Default: 1800
+    #[arg(long, env = "KIRYUU_ANNOUNCE_INTERVAL")]
+    pub announce_interval: Option<u32>,
+
+    /// Seconds clients are told (`min interval`) they must wait between announces, at most --announce-interval. Default: same as --announce-interval
+    #[arg(long, env = "KIRYUU_MIN_ANNOUNCE_INTERVAL")]
+    pub min_announce_interval: Option<u32>,
+
+    /// How to enforce --min-announce-interval, per kiryuu or through the store. Default: memory
+    #[arg(long, value_enum, env = "KIRYUU_RATE_LIMIT")]
+    pub rate_limit: Option<rate_limit::Mode>,
+
+    /// Seconds since their last announce peers are still handed out, more than --announce-interval. Default: 1860
+    #[arg(long, env = "KIRYUU_PEER_WINDOW")]
+    pub peer_window: Option<u64>,
+
+    /// Seconds a cached announce reply lives, unless the torrent's peers change first. Default: 1800
+    #[arg(long, env = "KIRYUU_CACHE_TTL")]
+    pub cache_ttl: Option<u32>,
+
+    /// Seconds between pruning stale peers & torrents. Default: 60
+    #[arg(long, env = "KIRYUU_REAP_INTERVAL")]
+    pub reap_interval: Option<u64>,
+
+    /// Torrents to prune per step. Default: 1000
+    #[arg(long, env = "KIRYUU_REAP_BATCH_SIZE")]
+    pub reap_batch_size: Option<u32>,
+
+    /// Seconds without an announce before a torrent (and its stats) is dropped, at least --peer-window. Default: 604800 (a week)
+    #[arg(long, env = "KIRYUU_TORRENT_TTL")]
+    pub torrent_ttl: Option<u64>,
+
+    /// Most new HTTP connections each worker accepts at once. Default: 8192
+    #[arg(long, env = "KIRYUU_MAX_CONNECTION_RATE")]
+    pub max_connection_rate: Option<usize>,
+
+    /// Milliseconds a client gets to send its request headers, 0 for no limit. Default: 1000
+    #[arg(long, env = "KIRYUU_CLIENT_REQUEST_TIMEOUT")]
+    pub client_request_timeout: Option<u64>,
+
+    /// Seconds between writing the global stats (announce counts etc.) to the store. Default: 10
+    #[arg(long, env = "KIRYUU_STATS_FLUSH_INTERVAL")]
+    pub stats_flush_interval: Option<u64>,
+
+    /// Only serve announces to `/<passkey>/announce`, for the passkeys in --passkeys-redis-hash
+    #[arg(long, env = "KIRYUU_PRIVATE")]
+    pub private: bool,
+
+    /// Redis hash of passkey => user id, for --private. Default: kiryuu_passkeys
+    #[arg(long, env = "KIRYUU_PASSKEYS_REDIS_HASH")]
+    pub passkeys_redis_hash: Option<String>,
+
+    /// Secret that signs stateless passkeys (see `mint-passkey`), checked without a redis round trip
+    #[arg(long, env = "KIRYUU_PASSKEY_SECRET")]
+    pub passkey_secret: Option<String>,
+
+    /// Passkeys, or user ids, to refuse in private mode. Reloaded on SIGHUP
+    #[arg(long, value_delimiter = ',', env = "KIRYUU_REVOKED_PASSKEYS")]
+    pub revoked_passkeys: Vec<String>,
+
+    /// File of the only infohashes to track, one (hex) per line
+    #[arg(long, env = "KIRYUU_ALLOWLIST_FILE")]
+    pub allowlist_file: Option<PathBuf>,
+
+    /// Redis set of the only infohashes to track, on top of --allowlist-file
+    #[arg(long, env = "KIRYUU_ALLOWLIST_REDIS_SET")]
+    pub allowlist_redis_set: Option<String>,
+
+    /// File of infohashes to refuse, one (hex) per line
+    #[arg(long, env = "KIRYUU_DENYLIST_FILE")]
+    pub denylist_file: Option<PathBuf>,
+
+    /// Redis set of infohashes to refuse, on top of --denylist-file
+    #[arg(long, env = "KIRYUU_DENYLIST_REDIS_SET")]
+    pub denylist_redis_set: Option<String>,
+
+    /// Seconds between re-reading the allow/denylists. Default: 60
+    #[arg(long, env = "KIRYUU_LIST_REFRESH_INTERVAL")]
+    pub list_refresh_interval: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[cfg(feature = "tracing")]
+    /// Address of jaeger. Default: 127.0.0.1:6831
+    #[arg(long, env = "KIRYUU_JAEGER_HOST")]
+    pub jaeger_host: Option<String>,
+
+    #[cfg(feature = "tracing")]
+    /// Token for aspecto.io
+    #[arg(long, env = "KIRYUU_ASPECTO_TOKEN")]
+    pub aspecto_token: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Move torrents to the redis shard they hash to, after adding a --redis-host. Then exits.
+    Rebalance,
+    /// Print a passkey for the user, signed with --passkey-secret. Then exits.
+    MintPasskey {
+        /// Letters, digits, _ and -
+        #[arg(long)]
+        user: String,
+        /// Seconds until it expires. Default: never
+        #[arg(long)]
+        expires_in: Option<u64>,
+    },
+}
+
+/// What the `--config` file can set, all optional
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct File {
+    port: Option<u16>,
+    host: Option<String>,
+    store: Option<store::Backend>,
+    redis_host: Option<Vec<String>>,
+    redis_cluster: Option<bool>,
+    max_numwant: Option<u16>,
+    default_numwant: Option<u16>,
+    peer_selection: Option<peer_selection::Strategy>,
+    udp_port: Option<u16>,
+    disable_udp: Option<bool>,
+    announce_interval: Option<u32>,
+    min_announce_interval: Option<u32>,
+    rate_limit: Option<rate_limit::Mode>,
+    peer_window: Option<u64>,
+    cache_ttl: Option<u32>,
+    reap_interval: Option<u64>,
+    reap_batch_size: Option<u32>,
+    torrent_ttl: Option<u64>,
+    max_connection_rate: Option<usize>,
+    client_request_timeout: Option<u64>,
+    stats_flush_interval: Option<u64>,
+    private: Option<bool>,
+    passkeys_redis_hash: Option<String>,
+    passkey_secret: Option<String>,
+    revoked_passkeys: Option<Vec<String>>,
+    allowlist_file: Option<PathBuf>,
+    allowlist_redis_set: Option<String>,
+    denylist_file: Option<PathBuf>,
+    denylist_redis_set: Option<String>,
+    list_refresh_interval: Option<u64>,
+    jaeger_host: Option<String>,
+    aspecto_token: Option<String>,
+}
+
+/// The effective settings
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Config {
+    pub port: u16,
+    pub host: String,
+    pub store: store::Backend,
+    pub redis_host: Vec<String>,
+    pub redis_cluster: bool,
+    pub max_numwant: u16,
+    pub default_numwant: u16,
+    pub peer_selection: peer_selection::Strategy,
+    pub udp_port: u16,
+    pub disable_udp: bool,
+    /// Seconds
+    pub announce_interval: u32,
+    /// Seconds
+    pub min_announce_interval: u32,
+    pub rate_limit: rate_limit::Mode,
+    /// Seconds
+    pub peer_window: u64,
+    /// Seconds
+    pub cache_ttl: u32,
+    /// Seconds
+    pub reap_interval: u64,
+    pub reap_batch_size: u32,
+    /// Seconds
+    pub torrent_ttl: u64,
+    pub max_connection_rate: usize,
+    /// Milliseconds
+    pub client_request_timeout: u64,
+    /// Seconds
+    pub stats_flush_interval: u64,
+    pub private: bool,
+    pub passkeys_redis_hash: String,
+    #[serde(serialize_with = "redact")]
+    pub passkey_secret: Option<String>,
+    pub revoked_passkeys: Vec<String>,
+    pub allowlist_file: Option<PathBuf>,
+    pub allowlist_redis_set: Option<String>,
+    pub denylist_file: Option<PathBuf>,
+    pub denylist_redis_set: Option<String>,
+    /// Seconds
+    pub list_refresh_interval: u64,
+    pub jaeger_host: Option<String>,
+    #[serde(serialize_with = "redact")]
+    pub aspecto_token: Option<String>,
+}
+
+fn redact<S: serde::Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    return match secret {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    };
+}
+
+impl Config {
+    /// Layer the flags & environment (`args`) over the `--config` file, then validate
+    pub fn load(args: &Args) -> Result<Config, String> {
+        let file = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+                toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))?
+            },
+            None => File::default(),
+        };
+
+        return Config::layer(args, file);
+    }
+
+    fn layer(args: &Args, file: File) -> Result<Config, String> {
+        #[cfg(feature = "tracing")]
+        let (jaeger_host, aspecto_token) = (args.jaeger_host.clone().or(file.jaeger_host), args.aspecto_token.clone().or(file.aspecto_token));
+        #[cfg(not(feature = "tracing"))]
+        let (jaeger_host, aspecto_token) = (file.jaeger_host, file.aspecto_token);
+
+        let port = args.port.or(file.port).unwrap_or(6969);
+        let announce_interval = args.announce_interval.or(file.announce_interval).unwrap_or(1800);
+
+        let config = Config {
+            port,
+            host: args.host.clone().or(file.host).unwrap_or_else(|| "0.0.0.0".to_string()),
+            store: args.store.or(file.store).unwrap_or(store::Backend::Redis),
+            redis_host: match args.redis_host.is_empty() {
+                true => file.redis_host.unwrap_or_else(|| vec!["127.0.0.1:6379".to_string()]),
+                false => args.redis_host.clone(),
+            },
+            redis_cluster: args.redis_cluster || file.redis_cluster.unwrap_or(false),
+            max_numwant: args.max_numwant.or(file.max_numwant).unwrap_or(200),
+            default_numwant: args.default_numwant.or(file.default_numwant).unwrap_or(swarm::DEFAULT_NUMWANT),
+            peer_selection: args.peer_selection.or(file.peer_selection).unwrap_or(peer_selection::Strategy::Random),
+            udp_port: args.udp_port.or(file.udp_port).unwrap_or(port),
+            disable_udp: args.disable_udp || file.disable_udp.unwrap_or(false),
+            announce_interval,
+            min_announce_interval: args.min_announce_interval.or(file.min_announce_interval).unwrap_or(announce_interval),
+            rate_limit: args.rate_limit.or(file.rate_limit).unwrap_or(rate_limit::Mode::Memory),
+            peer_window: args.peer_window.or(file.peer_window).unwrap_or(swarm::THIRTY_ONE_MINUTES as u64 / 1000),
+            cache_ttl: args.cache_ttl.or(file.cache_ttl).unwrap_or(60 * 30),
+            reap_interval: args.reap_interval.or(file.reap_interval).unwrap_or(60),
+            reap_batch_size: args.reap_batch_size.or(file.reap_batch_size).unwrap_or(1000),
+            torrent_ttl: args.torrent_ttl.or(file.torrent_ttl).unwrap_or(60 * 60 * 24 * 7),
+            max_connection_rate: args.max_connection_rate.or(file.max_connection_rate).unwrap_or(8192),
+            client_request_timeout: args.client_request_timeout.or(file.client_request_timeout).unwrap_or(1000),
+            stats_flush_interval: args.stats_flush_interval.or(file.stats_flush_interval).unwrap_or(10),
+            private: args.private || file.private.unwrap_or(false),
+            passkeys_redis_hash: args.passkeys_redis_hash.clone().or(file.passkeys_redis_hash).unwrap_or_else(|| "kiryuu_passkeys".to_string()),
+            passkey_secret: args.passkey_secret.clone().or(file.passkey_secret),
+            revoked_passkeys: match args.revoked_passkeys.is_empty() {
+                true => file.revoked_passkeys.unwrap_or_default(),
+                false => args.revoked_passkeys.clone(),
+            },
+            allowlist_file: args.allowlist_file.clone().or(file.allowlist_file),
+            allowlist_redis_set: args.allowlist_redis_set.clone().or(file.allowlist_redis_set),
+            denylist_file: args.denylist_file.clone().or(file.denylist_file),
+            denylist_redis_set: args.denylist_redis_set.clone().or(file.denylist_redis_set),
+            list_refresh_interval: args.list_refresh_interval.or(file.list_refresh_interval).unwrap_or(60),
+            jaeger_host,
+            aspecto_token,
+        };
+
+        config.validate()?;
+        return Ok(config);
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=999).contains(&self.max_numwant) {
+            return Err("max_numwant must be 1-999".to_string());
+        }
+        if self.default_numwant > self.max_numwant {
+            return Err("default_numwant can't be more than max_numwant".to_string());
+        }
+        if self.redis_host.iter().all(|host| host.trim().is_empty()) {
+            return Err("redis_host can't be empty".to_string());
+        }
+        if self.announce_interval == 0 {
+            return Err("announce_interval must be at least 1".to_string());
+        }
+        if self.min_announce_interval > self.announce_interval {
+            return Err("min_announce_interval can't be more than announce_interval".to_string());
+        }
+        // Otherwise peers drop out of replies between their announces
+        if self.peer_window <= self.announce_interval as u64 {
+            return Err("peer_window must be more than announce_interval".to_string());
+        }
+        if self.torrent_ttl < self.peer_window {
+            return Err("torrent_ttl can't be less than peer_window".to_string());
+        }
+        if self.cache_ttl == 0 || self.reap_interval == 0 || self.reap_batch_size == 0 || self.max_connection_rate == 0 || self.stats_flush_interval == 0 || self.list_refresh_interval == 0 {
+            return Err("cache_ttl, reap_interval, reap_batch_size, max_connection_rate, stats_flush_interval and list_refresh_interval must be at least 1".to_string());
+        }
+        if self.store != store::Backend::Redis && self.private && self.passkey_secret.is_none() {
+            return Err("private needs the redis store for the passkeys, or a passkey_secret".to_string());
+        }
+        if self.passkey_secret.as_ref().is_some_and(|secret| secret.len() < 16) {
+            return Err("passkey_secret must be at least 16 characters".to_string());
+        }
+        if self.store != store::Backend::Redis && (self.allowlist_redis_set.is_some() || self.denylist_redis_set.is_some()) {
+            return Err("allowlist_redis_set and denylist_redis_set need the redis store".to_string());
+        }
+
+        return Ok(());
+    }
+
+    /// The peer window in ms, which is how the store scores peers
+    pub fn peer_window_ms(&self) -> i64 {
+        return self.peer_window as i64 * 1000;
+    }
+
+    /// `self` with what `new` changed that `Settings` covers,
+    /// and the names of the other changed settings, which only take effect on restart.
+    /// Errs if that mix isn't valid, e.g. a `peer_window` past the running `torrent_ttl`.
+    pub fn reload(&self, new: Config) -> Result<(Config, Vec<&'static str>), String> {
+        let changed = [
+            ("port", self.port != new.port),
+            ("host", self.host != new.host),
+            ("store", self.store != new.store),
+            ("redis_host", self.redis_host != new.redis_host),
+            ("redis_cluster", self.redis_cluster != new.redis_cluster),
+            // The reply sizes are part of the cache keys, so the store relies on them
+            ("max_numwant", self.max_numwant != new.max_numwant),
+            ("udp_port", self.udp_port != new.udp_port),
+            ("disable_udp", self.disable_udp != new.disable_udp),
+            ("rate_limit", self.rate_limit != new.rate_limit),
+            ("cache_ttl", self.cache_ttl != new.cache_ttl),
+            ("reap_interval", self.reap_interval != new.reap_interval),
+            ("reap_batch_size", self.reap_batch_size != new.reap_batch_size),
+            ("torrent_ttl", self.torrent_ttl != new.torrent_ttl),
+            ("max_connection_rate", self.max_connection_rate != new.max_connection_rate),
+            ("client_request_timeout", self.client_request_timeout != new.client_request_timeout),
+            ("stats_flush_interval", self.stats_flush_interval != new.stats_flush_interval),
+            ("private", self.private != new.private),
+            ("passkeys_redis_hash", self.passkeys_redis_hash != new.passkeys_redis_hash),
+            ("passkey_secret", self.passkey_secret != new.passkey_secret),
+            // The lists themselves are re-read every list_refresh_interval, not on SIGHUP
+            ("allowlist_file", self.allowlist_file != new.allowlist_file),
+            ("allowlist_redis_set", self.allowlist_redis_set != new.allowlist_redis_set),
+            ("denylist_file", self.denylist_file != new.denylist_file),
+            ("denylist_redis_set", self.denylist_redis_set != new.denylist_redis_set),
+            ("list_refresh_interval", self.list_refresh_interval != new.list_refresh_interval),
+            ("jaeger_host", self.jaeger_host != new.jaeger_host),
+            ("aspecto_token", self.aspecto_token != new.aspecto_token),
+        ];
+
+        let reloaded = Config {
+            default_numwant: new.default_numwant,
+            peer_selection: new.peer_selection,
+            announce_interval: new.announce_interval,
+            min_announce_interval: new.min_announce_interval,
+            peer_window: new.peer_window,
+            revoked_passkeys: new.revoked_passkeys,
+            ..self.clone()
+        };
+        reloaded.validate()?;
+
+        return Ok((reloaded, changed.into_iter().filter(|(_, changed)| *changed).map(|(name, _)| name).collect()));
+    }
+}
+
+/// The settings `announce` (and the reaper) read on each request, swapped on reload
+pub struct Settings {
+    pub numwant_buckets: swarm::NumwantBuckets,
+    pub peer_selection: Box<dyn peer_selection::PeerSelection>,
+    /// Seconds, what clients are told to wait between announces
+    pub announce_interval: u32,
+    /// Seconds, earlier announces are refused (unless --rate-limit is off)
+    pub min_announce_interval: u32,
+    /// Peers that last announced longer ago than this aren't handed out
+    pub peer_window_ms: i64,
+    /// Passkeys & user ids refused in private mode
+    pub revoked_passkeys: HashSet<String>,
+}
+
+impl Settings {
+    pub fn new(config: &Config) -> Settings {
+        return Settings {
+            numwant_buckets: swarm::NumwantBuckets::new(config.max_numwant, config.default_numwant),
+            peer_selection: peer_selection::new(config.peer_selection),
+            announce_interval: config.announce_interval,
+            min_announce_interval: config.min_announce_interval,
+            peer_window_ms: config.peer_window_ms(),
+            revoked_passkeys: config.revoked_passkeys.iter().cloned().collect(),
+        };
+    }
+}
+
+/// Readers grab the current `Settings` (an `Arc` clone) and keep them for the whole request,
+/// so a reload never changes them halfway through one
+pub struct LiveSettings(RwLock<Arc<Settings>>);
+
+impl LiveSettings {
+    pub fn new(settings: Settings) -> LiveSettings {
+        return LiveSettings(RwLock::new(Arc::new(settings)));
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        return self.0.read().expect("poisoned settings").clone();
+    }
+
+    fn set(&self, settings: Settings) {
+        *self.0.write().expect("poisoned settings") = Arc::new(settings);
+    }
+}
+
+/// Reload the config on every SIGHUP, keeping the current one if the new one is invalid
+#[cfg(unix)]
+pub async fn reload_on_sighup(args: Args, mut current: Config, data: web::Data<AppState>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            println!("Err listening for SIGHUP, config reloading is off {}", e);
+            return;
+        },
+    };
+
+    while hangups.recv().await.is_some() {
+        let (reloaded, needs_restart) = match Config::load(&args).and_then(|new| current.reload(new)) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                println!("Err reloading config, keeping the current one: {}", e);
+                continue;
+            },
+        };
+
+        if !needs_restart.is_empty() {
+            println!("Config reloaded, but these only change on restart: {}", needs_restart.join(", "));
+        } else {
+            println!("Config reloaded");
+        }
+
+        data.settings.set(Settings::new(&reloaded));
+        current = reloaded;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn layer(flags: &[&str], file: &str) -> Result<Config, String> {
+        let args = Args::try_parse_from([&["kiryuu"], flags].concat()).expect("valid flags");
+        return Config::layer(&args, toml::from_str(file).map_err(|e| e.to_string())?);
+    }
+
+    #[test]
+    fn defaults() {
+        let config = layer(&[], "").unwrap();
+
+        assert_eq!(6969, config.port);
+        assert_eq!(6969, config.udp_port);
+        assert_eq!(vec!["127.0.0.1:6379".to_string()], config.redis_host);
+        assert_eq!(1800, config.announce_interval);
+        assert_eq!(1800, config.min_announce_interval);
+        assert_eq!(rate_limit::Mode::Memory, config.rate_limit);
+        assert_eq!(swarm::THIRTY_ONE_MINUTES, config.peer_window_ms());
+        assert_eq!(50, config.default_numwant);
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let file = "port = 1234\nannounce_interval = 900\nredis_host = ['a:1', 'b:2']\ndisable_udp = true";
+        let config = layer(&["--port", "4321"], file).unwrap();
+
+        assert_eq!(4321, config.port);
+        assert_eq!(4321, config.udp_port);
+        assert_eq!(900, config.announce_interval);
+        // Follows announce_interval
+        assert_eq!(900, config.min_announce_interval);
+        assert_eq!(vec!["a:1".to_string(), "b:2".to_string()], config.redis_host);
+        assert!(config.disable_udp);
+
+        let config = layer(&["--redis-host", "c:3,d:4"], file).unwrap();
+        assert_eq!(vec!["c:3".to_string(), "d:4".to_string()], config.redis_host);
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(layer(&[], "prot = 1234").is_err());
+        assert!(layer(&[], "peer_selection = 'best'").is_err());
+        assert!(layer(&["--max-numwant", "1000"], "").is_err());
+        assert!(layer(&["--announce-interval", "3600"], "").is_err());
+        assert!(layer(&[], "peer_window = 3600\ntorrent_ttl = 1860").is_err());
+        assert!(layer(&["--default-numwant", "300"], "").is_err());
+        assert!(layer(&["--min-announce-interval", "3600"], "").is_err());
+        assert!(layer(&["--store", "memory", "--denylist-redis-set", "dmca"], "").is_err());
+        assert!(layer(&["--store", "memory", "--private"], "").is_err());
+        assert!(layer(&["--store", "memory", "--private", "--passkey-secret", "0123456789abcdef"], "").is_ok());
+        assert!(layer(&["--passkey-secret", "short"], "").is_err());
+    }
+
+    #[test]
+    fn reloads_what_it_can() {
+        let current = layer(&[], "").unwrap();
+        let new = layer(&[], "port = 1234\nmax_numwant = 100\nannounce_interval = 900\npeer_selection = 'oldest'\nrevoked_passkeys = ['42']").unwrap();
+
+        let (reloaded, needs_restart) = current.reload(new).unwrap();
+        // udp_port follows port
+        assert_eq!(vec!["port", "max_numwant", "udp_port"], needs_restart);
+        assert_eq!(6969, reloaded.port);
+        assert_eq!(200, reloaded.max_numwant);
+        assert_eq!(900, reloaded.announce_interval);
+        assert_eq!(peer_selection::Strategy::Oldest, reloaded.peer_selection);
+        assert_eq!(vec!["42".to_string()], reloaded.revoked_passkeys);
+
+        // Valid on its own, but not with the running torrent_ttl
+        let current = layer(&["--torrent-ttl", "2000"], "").unwrap();
+        let new = layer(&[], "peer_window = 3600").unwrap();
+        assert!(current.reload(new).is_err());
+    }
+}
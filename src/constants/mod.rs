@@ -1,5 +1,6 @@
 pub const ANNOUNCE_COUNT_KEY: &str = "kiryuu_http_announce_count";
 pub const NOCHANGE_ANNOUNCE_COUNT_KEY: &str = "kiryuu_http_nochange_announce_count"; // If no change to seeder_count / leecher_count
 pub const CACHE_HIT_ANNOUNCE_COUNT_KEY: &str = "kiryuu_http_cache_hit_announce_count";
+pub const STARTED_ANNOUNCE_COUNT_KEY: &str = "kiryuu_http_started_announce_count"; // New sessions
 pub const REQ_DURATION_KEY: &str = "kiryuu_http_req_seconds_sum";
 pub const TORRENTS_KEY: &str = "TORRENTS";
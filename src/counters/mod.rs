@@ -0,0 +1,117 @@
+// The global stats in `constants` are added up here, and flushed to the store every
+// `--stats-flush-interval` (and on shutdown), instead of an INCR per announce.
+// The keys are the same as before, so whatever reads them keeps working.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use actix_web::web;
+
+use crate::store::Mutation;
+use crate::{constants, AppState};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Counter {
+    Announces,
+    /// Announces that didn't change the seeder / leecher counts
+    NoChangeAnnounces,
+    CacheHitAnnounces,
+    /// Milliseconds spent handling announces
+    AnnounceDuration,
+    /// Announces with `event=started`, i.e. new sessions
+    StartedAnnounces,
+}
+
+const COUNTERS: [Counter; 5] = [Counter::Announces, Counter::NoChangeAnnounces, Counter::CacheHitAnnounces, Counter::AnnounceDuration, Counter::StartedAnnounces];
+
+impl Counter {
+    fn key(&self) -> &'static str {
+        return match self {
+            Counter::Announces => constants::ANNOUNCE_COUNT_KEY,
+            Counter::NoChangeAnnounces => constants::NOCHANGE_ANNOUNCE_COUNT_KEY,
+            Counter::CacheHitAnnounces => constants::CACHE_HIT_ANNOUNCE_COUNT_KEY,
+            Counter::AnnounceDuration => constants::REQ_DURATION_KEY,
+            Counter::StartedAnnounces => constants::STARTED_ANNOUNCE_COUNT_KEY,
+        };
+    }
+}
+
+#[derive(Default)]
+pub struct Counters {
+    counts: [AtomicI64; COUNTERS.len()],
+}
+
+impl Counters {
+    pub fn add(&self, counter: Counter, by: i64) {
+        self.counts[counter as usize].fetch_add(by, Ordering::Relaxed);
+    }
+
+    /// What was added since the last take, as mutations for the store
+    fn take(&self) -> Vec<Mutation> {
+        return COUNTERS.iter().filter_map(|&counter| {
+            let by = self.counts[counter as usize].swap(0, Ordering::Relaxed);
+            return (by != 0).then_some(Mutation::IncrementStat { key: counter.key(), by });
+        }).collect();
+    }
+
+    /// Put back what `take` gave, when the store couldn't take it
+    fn put_back(&self, mutations: &[Mutation]) {
+        for mutation in mutations {
+            if let Mutation::IncrementStat { key, by } = mutation {
+                if let Some(&counter) = COUNTERS.iter().find(|counter| counter.key() == *key) {
+                    self.add(counter, *by);
+                }
+            }
+        }
+    }
+}
+
+/// Write what's been counted to the store. If that fails it's kept for the next flush.
+pub async fn flush(data: &AppState) {
+    let mutations = data.counters.take();
+    if mutations.is_empty() {
+        return;
+    }
+
+    if let Err(e) = data.store.apply(mutations.clone()).await {
+        println!("Err flushing stats {}", e);
+        data.counters.put_back(&mutations);
+    }
+}
+
+/// Flush forever, every `interval`
+pub async fn run(data: web::Data<AppState>, interval: Duration) {
+    let mut interval = actix_web::rt::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+        flush(&data).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_and_puts_back() {
+        let counters = Counters::default();
+        counters.add(Counter::Announces, 1);
+        counters.add(Counter::Announces, 1);
+        counters.add(Counter::AnnounceDuration, 7);
+
+        let taken = counters.take();
+        assert_eq!(vec![
+            Mutation::IncrementStat { key: constants::ANNOUNCE_COUNT_KEY, by: 2 },
+            Mutation::IncrementStat { key: constants::REQ_DURATION_KEY, by: 7 },
+        ], taken);
+        assert!(counters.take().is_empty());
+
+        counters.add(Counter::Announces, 1);
+        counters.put_back(&taken);
+        assert_eq!(vec![
+            Mutation::IncrementStat { key: constants::ANNOUNCE_COUNT_KEY, by: 3 },
+            Mutation::IncrementStat { key: constants::REQ_DURATION_KEY, by: 7 },
+        ], counters.take());
+    }
+}
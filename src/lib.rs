@@ -1,3 +1,6 @@
+#![allow(clippy::needless_return)]
+
 pub mod byte_functions;
 pub mod query;
 pub mod constants;
+pub mod swarm;
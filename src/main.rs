@@ -1,11 +1,31 @@
+#![allow(clippy::needless_return)]
+
+mod access;
 mod byte_functions;
+mod config;
 mod query;
 mod constants;
+mod counters;
+mod metrics;
+mod peer_selection;
+mod private;
+mod rate_limit;
+mod reaper;
+mod store;
+mod swarm;
+mod udp;
+// Not wired up for now, see the commented out PUBLISH in `announce`
+#[allow(dead_code)]
 mod req_log;
 
 use actix_web::{get, App, HttpServer, web, HttpRequest, HttpResponse, http::header, http::StatusCode, dev::Service};
+use futures::future;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use clap::Parser;
+
+use config::{Args, Command, Config, LiveSettings, Settings};
+#[cfg(feature = "tracing")]
 use std::collections::HashMap;
 
 #[cfg(feature = "tracing")]
@@ -18,197 +38,174 @@ use opentelemetry::trace::Span;
 // This will acutally always be imported, has the feature flag
 // inside the macro.
 mod tracing;
-/// Simple
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Port for tracker to listen on. Default: 6969
-    #[arg(long)]
-    port: Option<u16>,
-
-    /// IP to bind tracker to. Default: 0.0.0.0
-    #[arg(long)]
-    host: Option<String>,
-
-    /// Address of redis instance. Default: 127.0.0.1:6379
-    #[arg(long)]
-    redis_host: Option<String>,
-
-    #[cfg(feature = "tracing")]
-    /// Address of jaeger
-    #[arg(long)]
-    jaeger_host: Option<String>,
+// Clients only show the user a `failure reason` from a bencoded 200 reply,
+// so that's how we report every error
+fn failure(reason: &str, retry_in: Option<query::RetryIn>) -> HttpResponse {
+    return HttpResponse::build(StatusCode::OK).append_header(header::ContentType::plaintext()).body(query::failure_reply(reason, retry_in));
+}
 
-    #[cfg(feature = "tracing")]
-    /// Token for aspecto.io
-    #[arg(long)]
-    aspecto_token: Option<String>,
+fn backend_failure(e: store::StoreError) -> HttpResponse {
+    println!("Err talking to store {}", e);
+    return failure("Tracker backend unavailable", Some(query::RetryIn::Minutes(1)));
 }
 
-// If not more than 31, possible not online
-// So dont waste bandwidth on redis query etc.
-const THIRTY_ONE_MINUTES: i64 = 60 * 31 * 1000;
+#[get("/announce")]
+async fn announce(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let started = std::time::Instant::now();
+    let (response, outcome) = handle_announce(req, None, &data).await;
+    data.metrics.observe_request("http", "announce", outcome, started);
 
-#[derive(Debug)]
-enum Exists {
-    Yes,
-    No,
+    return response;
 }
 
-impl From<&Exists> for bool {
-    fn from(item: &Exists) -> Self {
-        match item {
-            Exists::Yes => true,
-            Exists::No => false,
-        }
-    }
-}
+/// Only served in private mode
+#[get("/{passkey}/announce")]
+async fn private_announce(req: HttpRequest, passkey: web::Path<String>, data: web::Data<AppState>) -> HttpResponse {
+    let started = std::time::Instant::now();
+    let (response, outcome) = handle_announce(req, Some(&passkey), &data).await;
+    data.metrics.observe_request("http", "announce", outcome, started);
 
-impl redis::FromRedisValue for Exists {
-    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Exists> {
-        match *v {
-            redis::Value::Nil => Ok(Exists::No),
-            _ => Ok(Exists::Yes),
-        }
-    }
+    return response;
 }
 
-#[get("/announce")]
-async fn announce(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {    
-  
+async fn handle_announce(req: HttpRequest, passkey: Option<&str>, data: &web::Data<AppState>) -> (HttpResponse, metrics::Outcome) {
     let time_now = SystemTime::now().duration_since(UNIX_EPOCH).expect("fucked up");
     let time_now_ms: i64 = i64::try_from(time_now.as_millis()).expect("fucc");
-    let max_limit = time_now_ms - THIRTY_ONE_MINUTES;
+    // This request sticks to these, even if the config is reloaded meanwhile
+    let settings = data.settings.get();
 
     let query = req.query_string();
     let peer_addr = req.peer_addr();
 
     let user_ip = if let Some(ref addr) = peer_addr {
-        match addr {
-            std::net::SocketAddr::V4(ref v4_addr) => v4_addr.ip(),
-            _ => return HttpResponse::build(StatusCode::BAD_REQUEST).body("IPv6 not supported")
-        }
+        addr.ip()
     } else {
-        return HttpResponse::build(StatusCode::BAD_REQUEST).body("Missing IP")
+        return (failure("Missing IP", None), metrics::Outcome::Invalid);
     };
 
-    let parsed =  match query::parse_announce(user_ip, query.replace("%", "%25").as_bytes()) {
+    let parsed =  match query::parse_announce(&user_ip, query.replace("%", "%25").as_bytes()) {
         Ok(legit) => legit, // Just set `parsed` , let handler continue
-        Err(e) => match e {
-            query::QueryError::ParseFailure => {
-                return HttpResponse::build(StatusCode::BAD_REQUEST).body("Failed to parse announce\n");
-            },
-            query::QueryError::InvalidInfohash => {
-                return HttpResponse::build(StatusCode::BAD_REQUEST).body("Infohash is not 20 bytes\n");
-            }
-        }
+        Err(e) => return (failure(e.reason(), Some(query::RetryIn::Never)), metrics::Outcome::Invalid),
     };
 
-    // Get seeders & leechers
-    let mut rc = data.redis_connection.clone();
-    let (seeders_key, leechers_key, cache_key) = byte_functions::make_redis_keys(&parsed.info_hash);
-
-    let mut p = redis::pipe();
-    let pp = p.cmd("ZSCORE").arg(&seeders_key).arg(&parsed.ip_port)
-    .cmd("ZSCORE").arg(&leechers_key).arg(&parsed.ip_port)
-    .cmd("GET").arg(&cache_key);
-    
-    let (is_seeder_v2, is_leecher_v2, cached_reply) : (Exists, Exists, Vec<u8>) = trace_wrap_v2!(pp.query_async(&mut rc).await, "redis").unwrap();
+    if let Err(reason) = data.access.check(&parsed.info_hash) {
+        return (failure(reason, Some(query::RetryIn::Never)), metrics::Outcome::Denied);
+    }
 
-    let mut post_announce_pipeline = redis::pipe();
-    post_announce_pipeline.cmd("ZADD").arg(constants::TORRENTS_KEY).arg(time_now_ms).arg(&parsed.info_hash).ignore(); // To "update" the torrent
+    // Private mode, only for the passkeys we know
+    let user = match (&data.passkeys, passkey) {
+        (None, _) => None,
+        (Some(_), None) => return (failure("Announce with the URL that has your passkey", Some(query::RetryIn::Never)), metrics::Outcome::Unauthorized),
+        (Some(passkeys), Some(passkey)) => match passkeys.user(data.store.as_ref(), passkey, &settings.revoked_passkeys, time_now.as_secs()).await {
+            Ok(Ok(user)) => Some(user),
+            Ok(Err(reason)) => return (failure(reason, Some(query::RetryIn::Never)), metrics::Outcome::Unauthorized),
+            Err(e) => return (backend_failure(e), metrics::Outcome::BackendError),
+        },
+    };
 
-    // These will contain how we change the total number of seeders / leechers by the end of the announce
-    let mut seed_count_mod: i64 = 0;
-    let mut leech_count_mod: i64 = 0;
+    let numwant = settings.numwant_buckets.bucket(parsed.numwant);
 
+    // Too soon since the last one, refuse it before it costs any round trips to the swarm
+    match data.rate_limiter.check(data.store.as_ref(), &parsed, time_now_ms, settings.min_announce_interval as i64 * 1000).await {
+        Ok(None) => (),
+        Ok(Some(early_ms)) => return (failure("Announcing too often", Some(rate_limit::retry_in(early_ms))), metrics::Outcome::RateLimited),
+        Err(e) => return (backend_failure(e), metrics::Outcome::BackendError),
+    }
 
-    if let query::Event::Stopped = parsed.event {
-        if let Exists::Yes = is_seeder_v2 {
-            seed_count_mod -= 1;
-            post_announce_pipeline.cmd("ZREM").arg(&seeders_key).arg(&parsed.ip_port).ignore(); // We dont care about the return value
-        } else if let Exists::Yes = is_leecher_v2 {
-            leech_count_mod -= 1;
-            post_announce_pipeline.cmd("ZREM").arg(&leechers_key).arg(&parsed.ip_port).ignore(); // We dont care about the return value
-        }
-    } else if parsed.is_seeding {
-        // ZADD it regardless to update timestamp for the guy (in redis)
-        post_announce_pipeline.cmd("ZADD").arg(&seeders_key).arg(time_now_ms).arg(&parsed.ip_port).ignore();
+    let report = user.map(|user| private::Report::new(user, &parsed));
+    let (cached_peers, reply_mods) = match record_announce(data, &parsed, numwant, &settings, time_now_ms, report).await {
+        Ok(v) => v,
+        Err(e) => return (backend_failure(e), metrics::Outcome::BackendError),
+    };
 
-        // New seeder
-        if let Exists::No = is_seeder_v2 {
-            seed_count_mod += 1;
-        }
+    // Built per request (rather than cached as is), so the peer doesn't get itself back,
+    // and seeders only get leechers
+    let final_res = cached_peers.reply(&parsed, numwant, reply_mods.0, reply_mods.1, settings.announce_interval, settings.min_announce_interval);
 
-        // They just completed
-        if let query::Event::Completed = parsed.event {
-            // If they were previously leecher, remove from that pool
-            if let Exists::Yes = is_leecher_v2 {
-                post_announce_pipeline.cmd("ZREM").arg(&leechers_key).arg(&parsed.ip_port).ignore();
-                leech_count_mod -= 1
-            }
+    #[cfg(feature = "tracing")]
+    {
+        get_active_span(|span| {
+            let infohash = String::from_utf8_lossy(&parsed.info_hash.0).to_string();
+            let ip = match req.peer_addr() {
+                Some(val) => val.to_string(),
+                None => "NO_IP".to_string(),
+            };
+            span.set_attribute(Key::new("infohash").string(infohash));
+            span.set_attribute(Key::new("ip").string(ip));
+            span.add_event("finished", vec![]);
+        })
+    }
 
-            // Increment the downloaded count for the infohash stats
-            post_announce_pipeline.cmd("HINCRBY").arg(&parsed.info_hash).arg("downloaded").arg(1u32).ignore();
-        }
-    } else {
-        // ZADD it regardless to update timestamp for the guy (in redis)
-        post_announce_pipeline.cmd("ZADD").arg(&leechers_key).arg(time_now_ms).arg(&parsed.ip_port).ignore();
+    return (HttpResponse::build(StatusCode::OK).append_header(header::ContentType::plaintext()).body(final_res), metrics::Outcome::Ok);
+}
 
-        if let Exists::No = is_leecher_v2 {
-            leech_count_mod += 1;
-        };
-    } 
-
-    // Cache miss = query redis
-    // no change = update cache
-    // change = clear cache
-
-    let final_res = match cached_reply.len() {
-        0 => {
-            // Cache miss. Lookup from redis
-            let mut p = redis::pipe();
-            let pp = p.cmd("ZRANGEBYSCORE").arg(&seeders_key).arg(max_limit).arg(time_now_ms).arg("LIMIT").arg(0).arg(50)
-            .cmd("ZRANGEBYSCORE").arg(&leechers_key).arg(max_limit).arg(time_now_ms).arg("LIMIT").arg(0).arg(50);
-
-            let (seeders, leechers) : (Vec<Vec<u8>>, Vec<Vec<u8>>) = trace_wrap_v2!(pp.query_async(&mut rc).await, "redis").unwrap();
-        
-            // endex = end index XD. seems in rust cannot select first 50 elements, or limit to less if vector doesnt have 50
-            // e.g. &seeders[0..50] is panicking when seeders len is < 50. Oh well.
-            let seeder_endex = std::cmp::min(seeders.len(), 50);
-            let leecher_endex = std::cmp::min(leechers.len(), 50);
-
-            query::announce_reply(seeders.len() as i64 + seed_count_mod, leechers.len() as i64 + leech_count_mod, &seeders[0..seeder_endex], &leechers[0..leecher_endex])
+/// Record an announce that got past the checks, and get the peers to reply with (their counts
+/// still need the count mods we return added). The same for HTTP & UDP, so both keep the counters,
+/// the cache and the counts alike.
+async fn record_announce(data: &web::Data<AppState>, parsed: &query::PeerInfo, numwant: u16, settings: &Settings, time_now_ms: i64, report: Option<private::Report>) -> store::StoreResult<(query::CachedPeers, (i64, i64))> {
+    let max_limit = time_now_ms - settings.peer_window_ms;
+
+    // Record the announce, getting the cached reply in the same round trip
+    let announced = match trace_wrap_v2!(data.store.announce(parsed, numwant, time_now_ms).await, "redis") {
+        Ok(v) => v,
+        Err(e) => {
+            data.rate_limiter.forget(parsed);
+            return Err(e);
         },
-        _ => {
-            post_announce_pipeline.cmd("INCR").arg(constants::CACHE_HIT_ANNOUNCE_COUNT_KEY).ignore();
-            cached_reply
-        }
     };
+    let (seed_count_mod, leech_count_mod, partial_count_mod) = (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod);
 
-    // Is there a change in seeders / leechers
-    if seed_count_mod != 0 || leech_count_mod != 0 {
-        // TBD: Maybe we can issue the HINCRBY anyway, it is:
-        // Pipelined
-        // In background (not .awaited for announce reply)
-        // O(1) in redis
-        // Can clean up this branching crap
-        if seed_count_mod != 0 {
-            post_announce_pipeline.cmd("HINCRBY").arg(&parsed.info_hash).arg("seeders").arg(seed_count_mod).ignore();
-        }
+    // The store may not have indexed the torrent in the same go (see `Mutation::IndexTorrent`)
+    let mut mutations = vec![store::Mutation::IndexTorrent { info_hash: parsed.info_hash, time_ms: time_now_ms }];
 
-        if leech_count_mod != 0 {
-            post_announce_pipeline.cmd("HINCRBY").arg(&parsed.info_hash).arg("leechers").arg(leech_count_mod).ignore();
-        }
+    let cached_peers = announced.cached_reply.as_deref().and_then(query::CachedPeers::decode);
+    let plan = swarm::plan_reply(seed_count_mod, leech_count_mod, partial_count_mod, match cached_peers {
+        Some(_) => swarm::CacheState::Hit,
+        None => swarm::CacheState::Miss,
+    });
 
-        // TODO: Patch cached reply with the count mods?
-        // Also invalidate existing cache
-        post_announce_pipeline.cmd("DEL").arg(&cache_key).ignore();
-    } else {
-        post_announce_pipeline.cmd("INCR").arg(constants::NOCHANGE_ANNOUNCE_COUNT_KEY).ignore();
+    let (cached_peers, reply_mods) = match (cached_peers, plan.source) {
+        (Some(cached_peers), swarm::ReplySource::Cached { seed_count_mod, leech_count_mod, partial_count_mod }) => {
+            data.metrics.observe_cache(true);
+            data.counters.add(counters::Counter::CacheHitAnnounces, 1);
+            // Partial seeds are counted as incomplete
+            (cached_peers, (seed_count_mod, leech_count_mod + partial_count_mod))
+        },
+        _ => {
+            // Cache miss. Lookup from the store
+            data.metrics.observe_cache(false);
+            let families = [store::Family::V4, store::Family::V6];
+            // The counts are the torrent's stats, like scrapes get. Dual stack peers are in both families' sets,
+            // but only counted once there.
+            let info_hashes = [parsed.info_hash];
+            let fetched = future::try_join(
+                data.store.fetch_peers(&parsed.info_hash, &families, numwant, settings.peer_selection.as_ref(), max_limit, time_now_ms),
+                data.store.fetch_stats(&info_hashes),
+            );
+            let (peers, stats) = trace_wrap_v2!(fetched.await, "redis")?;
+            let (v4, v6, stats) = (&peers[0], &peers[1], &stats[0]);
+
+            // Partial seeds go in with the seeders, since they're no use to each other either.
+            // They're counted as incomplete, as in scrapes.
+            let cached_peers = query::CachedPeers {
+                seeders_count: stats.seeders,
+                leechers_count: stats.leechers + stats.partial_seeds,
+                seeders: [v4.seeders.concat(), v4.partial_seeds.concat()].concat(),
+                leechers: v4.leechers.concat(),
+                seeders6: [v6.seeders.concat(), v6.partial_seeds.concat()].concat(),
+                leechers6: v6.leechers.concat(),
+            };
+
+            (cached_peers, (0, 0))
+        },
+    };
+
+    // No change in seeders / leechers
+    if plan.cache {
+        data.counters.add(counters::Counter::NoChangeAnnounces, 1);
         // TBD: If we had a cache hit, any point to set it again? 
-        // For now we are ok, since background pipeline, O(1) in redis.
-        post_announce_pipeline.cmd("SET").arg(&cache_key).arg(&final_res).arg("EX").arg(60 * 30).ignore();
+        // For now we are ok, since applied in background, O(1) in redis.
+        mutations.push(store::Mutation::CacheReply { info_hash: parsed.info_hash, numwant, reply: cached_peers.encode() });
     }
 
 
@@ -217,10 +214,16 @@ async fn announce(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
 
     let req_duration = time_end_ms - time_now_ms;
 
-    post_announce_pipeline.cmd("INCR").arg(constants::ANNOUNCE_COUNT_KEY).ignore();
-    post_announce_pipeline.cmd("INCRBY").arg(constants::REQ_DURATION_KEY).arg(req_duration).ignore();
+    data.counters.add(counters::Counter::Announces, 1);
+    data.counters.add(counters::Counter::AnnounceDuration, req_duration);
+    if matches!(parsed.event, query::Event::Started) {
+        data.counters.add(counters::Counter::StartedAnnounces, 1);
+    }
+
 
+    let report_ttl_ms = settings.peer_window_ms;
 
+    let store_data = data.clone();
     actix_web::rt::spawn(async move {
         // log the summary
         // TODO: For now removed this since we no longer have string IP
@@ -228,50 +231,90 @@ async fn announce(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
         // post_announce_pipeline.cmd("PUBLISH").arg("reqlog").arg(req_log::generate_csv(&user_ip_owned, &parsed.info_hash)).ignore();
 
 
-        let () = match post_announce_pipeline.query_async::<redis::aio::MultiplexedConnection, ()>(&mut rc).await {
+        let () = match store_data.store.apply(mutations).await {
             Ok(_) => (),
             Err(e) => {
                 println!("Err during pipe {}. Timenow: {}, scountmod: {}, lcountmod: {}", e, time_now_ms, seed_count_mod, leech_count_mod);
-                ()
             },
         };
+
+        if let Some(report) = report {
+            if let Err(e) = store_data.store.account(report, report_ttl_ms).await {
+                println!("Err accounting transfer {}", e);
+            }
+        }
     });
 
-    #[cfg(feature = "tracing")]
-    {
-        get_active_span(|span| {
-            let infohash = String::from_utf8_lossy(&parsed.info_hash.0).to_string();
-            let ip = match req.peer_addr() {
-                Some(val) => val.to_string(),
-                None => "NO_IP".to_string(),
-            };
-            span.set_attribute(Key::new("infohash").string(infohash));
-            span.set_attribute(Key::new("ip").string(ip));
-            span.add_event("finished", vec![]);
-        })
+    return Ok((cached_peers, reply_mods));
+}
+
+#[get("/scrape")]
+async fn scrape(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    let started = std::time::Instant::now();
+    let (response, outcome) = handle_scrape(req, &data).await;
+    data.metrics.observe_request("http", "scrape", outcome, started);
+
+    return response;
+}
+
+async fn handle_scrape(req: HttpRequest, data: &web::Data<AppState>) -> (HttpResponse, metrics::Outcome) {
+    let mut info_hashes = match query::parse_scrape(req.query_string()) {
+        Ok(legit) => legit,
+        Err(e) => return (failure(e.reason(), Some(query::RetryIn::Never)), metrics::Outcome::Invalid),
+    };
+
+    info_hashes.truncate(swarm::MAX_SCRAPE_TORRENTS);
+
+    // Leave out the torrents we don't track, and only fail if that's all of them
+    let refused = info_hashes.iter().find_map(|info_hash| data.access.check(info_hash).err());
+    info_hashes.retain(|info_hash| data.access.check(info_hash).is_ok());
+    if let (true, Some(reason)) = (info_hashes.is_empty(), refused) {
+        return (failure(reason, Some(query::RetryIn::Never)), metrics::Outcome::Denied);
     }
+    let info_hashes = &info_hashes[..];
+
+    let stats = match trace_wrap_v2!(data.store.fetch_stats(info_hashes).await, "redis") {
+        Ok(stats) => stats,
+        Err(e) => return (backend_failure(e), metrics::Outcome::BackendError),
+    };
 
-    return HttpResponse::build(StatusCode::OK).append_header(header::ContentType::plaintext()).body(final_res);
+    // BEP 21: partial seeds are incomplete, but not downloaders
+    let files: Vec<query::ScrapeFile> = info_hashes.iter().zip(stats).map(|(info_hash, torrent)| {
+        (byte_functions::hex_to_raw_u8(&info_hash.0), torrent.seeders, torrent.downloaded, torrent.leechers + torrent.partial_seeds, torrent.leechers)
+    }).collect();
+
+    return (HttpResponse::build(StatusCode::OK).append_header(header::ContentType::plaintext()).body(query::scrape_reply(&files)), metrics::Outcome::Ok);
 }
 
 #[get("/healthz")]
 async fn healthz(data: web::Data<AppState>) -> HttpResponse {
-    let mut rc = data.redis_connection.clone();
-
-    match trace_wrap_v2!(redis::cmd("PING").query_async::<_, ()>(&mut rc).await, "redis-hc") {
+    match trace_wrap_v2!(data.store.ping().await, "redis-hc") {
         Ok(_) => HttpResponse::build(StatusCode::OK).append_header(header::ContentType::plaintext()).body("OK"),
-        Err(_) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).append_header(header::ContentType::plaintext()).body("OOF"),
+        // Still serving, announces get a failure with `retry in` until the store is back
+        Err(e) => HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).append_header(header::ContentType::plaintext()).body(format!("OOF {}", e)),
     }
 }
 
+#[get("/metrics")]
+async fn metrics_endpoint(data: web::Data<AppState>) -> HttpResponse {
+    return HttpResponse::build(StatusCode::OK).content_type(prometheus::TEXT_FORMAT).body(data.metrics.render());
+}
+
 struct AppState {
-    redis_connection: redis::aio::MultiplexedConnection,
+    store: Box<dyn store::SwarmStore>,
+    settings: LiveSettings,
+    metrics: Arc<metrics::Metrics>,
+    counters: counters::Counters,
+    rate_limiter: rate_limit::RateLimiter,
+    access: access::AccessLists,
+    /// How to check passkeys, in private mode
+    passkeys: Option<private::Passkeys>,
 }
 
 
 #[cfg(feature = "tracing")]
-fn init_tracer(args: &Args) -> Result<sdktrace::Tracer, TraceError> {
-    if let Some(aspecto_token) = &args.aspecto_token {
+fn init_tracer(config: &Config) -> Result<sdktrace::Tracer, TraceError> {
+    if let Some(aspecto_token) = &config.aspecto_token {
         let exporter = opentelemetry_otlp::new_exporter()
         .http()
         .with_endpoint("https://otelcol.aspecto.io/v1/traces")
@@ -292,7 +335,7 @@ fn init_tracer(args: &Args) -> Result<sdktrace::Tracer, TraceError> {
         ))
         .install_batch(opentelemetry::runtime::Tokio)
     } else {
-        let jaeger_host = args.jaeger_host.clone().unwrap_or_else(|| String::from("127.0.0.1:6831"));
+        let jaeger_host = config.jaeger_host.clone().unwrap_or_else(|| String::from("127.0.0.1:6831"));
         opentelemetry_jaeger::new_agent_pipeline()
         .with_endpoint(jaeger_host)
         .with_service_name("Kiryuu")
@@ -308,29 +351,164 @@ fn init_tracer(args: &Args) -> Result<sdktrace::Tracer, TraceError> {
     }
 }
 
+// Longest we wait between attempts to connect to redis on startup
+const MAX_CONNECT_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Keep trying until `connect` works, so the tracker doesn't need to be started after redis.
+/// Once connected, the connection manager(s) take care of reconnecting.
+async fn with_backoff<T, F, Fut>(mut connect: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = redis::RedisResult<T>>,
+{
+    let mut backoff = std::time::Duration::from_millis(500);
+
+    loop {
+        match connect().await {
+            Ok(connection) => return connection,
+            Err(e) => {
+                println!("Err connecting to redis {}, retrying in {:?}", e, backoff);
+                actix_web::rt::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_CONNECT_BACKOFF);
+            },
+        }
+    }
+}
+
+/// The (address, connection) of each shard. A cluster is one shard.
+async fn connect_redis(hosts: &[String], cluster: bool) -> std::io::Result<Vec<(String, store::RedisConnection)>> {
+    let hosts: Vec<String> = hosts.iter().map(|host| host.trim().to_string()).filter(|host| !host.is_empty()).collect();
+    let clients = hosts.iter().map(|host| redis::Client::open("redis://".to_string() + host))
+    .collect::<Result<Vec<_>, _>>().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if clients.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No redis hosts given"));
+    }
+
+    if cluster {
+        let connection = with_backoff(|| store::ClusterConnection::connect(hosts.clone())).await;
+        return Ok(vec![(hosts.join(","), store::RedisConnection::Cluster(connection))]);
+    }
+
+    let mut shards = Vec::with_capacity(hosts.len());
+    for (host, client) in hosts.into_iter().zip(clients) {
+        let connection = with_backoff(|| redis::aio::ConnectionManager::new(client.clone())).await;
+        shards.push((host, store::RedisConnection::Single(connection)));
+    }
+
+    return Ok(shards);
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid config: {}", e);
+            std::process::exit(2);
+        },
+    };
+
+    if args.check_config {
+        print!("{}", toml::to_string(&config).expect("config serializes"));
+        return Ok(());
+    }
 
     #[cfg(feature = "tracing")]
     {
-        let _tracer = init_tracer(&args).expect("Failed to initialise tracer.");
+        let _tracer = init_tracer(&config).expect("Failed to initialise tracer.");
+    }
+
+    if let Some(Command::MintPasskey { user, expires_in }) = &args.command {
+        let secret = match &config.passkey_secret {
+            Some(secret) => secret,
+            None => {
+                eprintln!("Minting passkeys needs a passkey_secret");
+                std::process::exit(2);
+            },
+        };
+        let expires = expires_in.map(|expires_in| SystemTime::now().duration_since(UNIX_EPOCH).expect("fucked up").as_secs() + expires_in);
+
+        match private::passkey::mint(secret.as_bytes(), user, expires) {
+            Ok(passkey) => println!("{}", passkey),
+            Err(e) => {
+                eprintln!("Can't mint a passkey: {}", e);
+                std::process::exit(2);
+            },
+        };
+        return Ok(());
+    }
+
+    let numwant_buckets = swarm::NumwantBuckets::new(config.max_numwant, config.default_numwant);
+
+    if let Some(Command::Rebalance) = args.command {
+        let shards = connect_redis(&config.redis_host, config.redis_cluster).await?;
+        let store = store::RedisStore::new(shards, numwant_buckets.all(), config.cache_ttl);
+
+        return match store.rebalance(config.reap_batch_size as usize).await {
+            Ok(stats) => {
+                println!("Moved {} torrents, {} peers", stats.torrents, stats.peers);
+                Ok(())
+            },
+            Err(e) => Err(std::io::Error::other(e.to_string())),
+        };
     }
 
-    let redis_host = args.redis_host.unwrap_or_else(|| "127.0.0.1:6379".to_string());
-    let redis = redis::Client::open("redis://".to_string() + &redis_host).unwrap();
-    let redis_connection = redis.get_multiplexed_tokio_connection().await.unwrap();
+    let store: Box<dyn store::SwarmStore> = match config.store {
+        store::Backend::Redis => {
+            let shards = connect_redis(&config.redis_host, config.redis_cluster).await?;
 
+            Box::new(store::RedisStore::new(shards, numwant_buckets.all(), config.cache_ttl))
+        },
+        store::Backend::Memory => Box::new(store::MemoryStore::new()),
+    };
+
+    let metrics = Arc::new(metrics::Metrics::new());
     let data = web::Data::new(AppState{
-        redis_connection,
+        store: Box::new(metrics::InstrumentedStore::new(store, metrics.clone())),
+        settings: LiveSettings::new(Settings::new(&config)),
+        metrics,
+        counters: counters::Counters::default(),
+        rate_limiter: rate_limit::RateLimiter::new(config.rate_limit),
+        access: access::AccessLists::new(
+            access::List::new(config.allowlist_file.clone(), config.allowlist_redis_set.clone()),
+            access::List::new(config.denylist_file.clone(), config.denylist_redis_set.clone()),
+        ),
+        passkeys: config.private.then(|| private::Passkeys {
+            redis_hash: config.passkeys_redis_hash.clone(),
+            secret: config.passkey_secret.as_ref().map(|secret| secret.as_bytes().to_vec()),
+        }),
     });
 
-    let port = args.port.unwrap_or_else(|| 6969);
-    let host = args.host.unwrap_or_else(|| "0.0.0.0".to_string());
+    // Don't start out tracking what the lists would refuse
+    if let Err(e) = data.access.load(data.store.as_ref()).await {
+        eprintln!("Invalid torrent lists: {}", e);
+        std::process::exit(2);
+    }
+
+    if !config.disable_udp {
+        let udp_socket = actix_web::rt::net::UdpSocket::bind((config.host.as_str(), config.udp_port)).await?;
+        actix_web::rt::spawn(udp::serve(udp_socket, data.clone()));
+    }
+
+    actix_web::rt::spawn(reaper::run(data.clone(), reaper::Config {
+        interval: std::time::Duration::from_secs(config.reap_interval),
+        batch_size: config.reap_batch_size as usize,
+        torrent_ttl: std::time::Duration::from_secs(config.torrent_ttl),
+    }));
+
+    actix_web::rt::spawn(access::run(data.clone(), std::time::Duration::from_secs(config.list_refresh_interval)));
+
+    actix_web::rt::spawn(counters::run(data.clone(), std::time::Duration::from_secs(config.stats_flush_interval)));
 
-    return HttpServer::new(move || {
+    #[cfg(unix)]
+    actix_web::rt::spawn(config::reload_on_sighup(args, config.clone(), data.clone()));
+
+    let server_data = data.clone();
+    let private = config.private;
+    let served = HttpServer::new(move || {
         App::new()
-        .app_data(data.clone())
+        .app_data(server_data.clone())
         .wrap_fn(|req, srv| {
             #[cfg(feature = "tracing")]
             {
@@ -354,11 +532,72 @@ async fn main() -> std::io::Result<()> {
         })
         .service(healthz)
         .service(announce)
+        .configure(|cfg| {
+            if private {
+                cfg.service(private_announce);
+            }
+        })
+        .service(scrape)
+        .service(metrics_endpoint)
     })
-    .bind((host, port))?
-    .max_connection_rate(8192)
+    .bind((config.host.as_str(), config.port))?
+    .max_connection_rate(config.max_connection_rate)
     .keep_alive(None)
-    .client_request_timeout(std::time::Duration::from_millis(1000))
+    .client_request_timeout(std::time::Duration::from_millis(config.client_request_timeout))
     .run()
     .await;
+
+    // Stopped (SIGINT / SIGTERM), don't lose what was counted since the last flush
+    counters::flush(&data).await;
+
+    return served;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    /// The app around a memory store, with the default settings
+    pub fn app_state(rate_limit: rate_limit::Mode) -> web::Data<AppState> {
+        let config = Config::load(&Args::try_parse_from(["kiryuu"]).expect("valid flags")).expect("valid config");
+
+        return web::Data::new(AppState {
+            store: Box::new(store::MemoryStore::new()),
+            settings: LiveSettings::new(Settings::new(&config)),
+            metrics: Arc::new(metrics::Metrics::new()),
+            counters: counters::Counters::default(),
+            rate_limiter: rate_limit::RateLimiter::new(rate_limit),
+            access: access::AccessLists::new(access::List::new(None, None), access::List::new(None, None)),
+            passkeys: None,
+        });
+    }
+
+    // The `complete` & `incomplete` of a bencoded reply
+    fn counts(body: &[u8]) -> (String, String) {
+        let body = String::from_utf8_lossy(body);
+        let count = |key: &str| body.split(key).nth(1).and_then(|rest| rest.split('e').next()).unwrap_or_default().to_string();
+        return (count("8:completei"), count("10:incompletei"));
+    }
+
+    #[actix_web::test]
+    async fn announce_counts_dual_stack_peers_like_scrape() {
+        let app = test::init_service(App::new().app_data(app_state(rate_limit::Mode::Off)).service(announce).service(scrape)).await;
+
+        // A dual stack seeder, then a leecher whose reply isn't cached
+        let mut reply = Vec::new();
+        for (addr, params) in [("127.0.0.1:1000", "left=0&ipv6=%3A%3A1"), ("127.0.0.2:1000", "left=10")] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/announce?info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&{}", params))
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            reply = test::call_and_read_body(&app, req).await.to_vec();
+        }
+
+        let req = test::TestRequest::get().uri("/scrape?info_hash=AAAAAAAAAAAAAAAAAAAA").to_request();
+        let scraped = test::call_and_read_body(&app, req).await;
+
+        assert_eq!(("1".to_string(), "1".to_string()), counts(&reply));
+        assert_eq!(counts(&scraped), counts(&reply));
+    }
 }
@@ -0,0 +1,236 @@
+// In-process metrics, served at `/metrics` in Prometheus' text format.
+// Requests are counted by outcome and timed in histograms, and so is every store call
+// (see `InstrumentedStore`), which for redis is a round trip.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+
+use crate::byte_functions::types::RawVal;
+use crate::peer_selection::PeerSelection;
+use crate::{private, query};
+use crate::store::{Announced, Family, FamilyPeers, Mutation, ReapStats, Role, StoreResult, SwarmStore, TorrentStats};
+
+// Seconds. Most requests should be a redis round trip or two.
+const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+/// How a request went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// Malformed, we replied with a failure
+    Invalid,
+    /// The store failed us
+    BackendError,
+    /// Announced again before `min interval` was up
+    RateLimited,
+    /// For a torrent the allow/denylist refuses
+    Denied,
+    /// Private mode, without a known passkey
+    Unauthorized,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        return match self {
+            Outcome::Ok => "ok",
+            Outcome::Invalid => "invalid",
+            Outcome::BackendError => "backend_error",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Denied => "denied",
+            Outcome::Unauthorized => "unauthorized",
+        };
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    cache: IntCounterVec,
+    store_duration: HistogramVec,
+    store_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("kiryuu_requests_total", "Announces & scrapes handled, by protocol and outcome"),
+            &["protocol", "request", "outcome"],
+        ).expect("valid metric");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("kiryuu_request_duration_seconds", "Time to handle announces & scrapes").buckets(LATENCY_BUCKETS.to_vec()),
+            &["protocol", "request"],
+        ).expect("valid metric");
+        let cache = IntCounterVec::new(
+            Opts::new("kiryuu_announce_cache_total", "HTTP announces answered from the reply cache (hit), or not (miss)"),
+            &["result"],
+        ).expect("valid metric");
+        let store_duration = HistogramVec::new(
+            HistogramOpts::new("kiryuu_store_duration_seconds", "Time per store call, a round trip for redis").buckets(LATENCY_BUCKETS.to_vec()),
+            &["op"],
+        ).expect("valid metric");
+        let store_errors = IntCounterVec::new(
+            Opts::new("kiryuu_store_errors_total", "Failed store calls. `apply` is the pipeline after each announce"),
+            &["op"],
+        ).expect("valid metric");
+
+        registry.register(Box::new(requests.clone())).expect("unique metric");
+        registry.register(Box::new(request_duration.clone())).expect("unique metric");
+        registry.register(Box::new(cache.clone())).expect("unique metric");
+        registry.register(Box::new(store_duration.clone())).expect("unique metric");
+        registry.register(Box::new(store_errors.clone())).expect("unique metric");
+
+        // CPU, memory, open fds, ... of kiryuu itself
+        #[cfg(target_os = "linux")]
+        registry.register(Box::new(prometheus::process_collector::ProcessCollector::for_self())).expect("unique metric");
+
+        return Metrics { registry, requests, request_duration, cache, store_duration, store_errors };
+    }
+
+    /// `protocol` is http or udp, `request` announce or scrape
+    pub fn observe_request(&self, protocol: &str, request: &str, outcome: Outcome, started: Instant) {
+        self.requests.with_label_values(&[protocol, request, outcome.label()]).inc();
+        self.request_duration.with_label_values(&[protocol, request]).observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn observe_cache(&self, hit: bool) {
+        self.cache.with_label_values(&[if hit { "hit" } else { "miss" }]).inc();
+    }
+
+    fn observe_store(&self, op: &str, started: Instant, failed: bool) {
+        self.store_duration.with_label_values(&[op]).observe(started.elapsed().as_secs_f64());
+        if failed {
+            self.store_errors.with_label_values(&[op]).inc();
+        }
+    }
+
+    /// Everything, in the Prometheus text format
+    pub fn render(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).expect("metrics encode");
+        return buffer;
+    }
+}
+
+/// Times (and counts the errors of) each call to the store it wraps
+pub struct InstrumentedStore {
+    inner: Box<dyn SwarmStore>,
+    metrics: Arc<Metrics>,
+}
+
+impl InstrumentedStore {
+    pub fn new(inner: Box<dyn SwarmStore>, metrics: Arc<Metrics>) -> InstrumentedStore {
+        return InstrumentedStore { inner, metrics };
+    }
+}
+
+#[async_trait(?Send)]
+impl SwarmStore for InstrumentedStore {
+    async fn announce(&self, parsed: &query::PeerInfo, numwant: u16, time_now_ms: i64) -> StoreResult<Announced> {
+        let started = Instant::now();
+        let result = self.inner.announce(parsed, numwant, time_now_ms).await;
+        self.metrics.observe_store("announce", started, result.is_err());
+        return result;
+    }
+
+    async fn fetch_peers(
+        &self,
+        info_hash: &RawVal<40>,
+        families: &[Family],
+        numwant: u16,
+        selection: &dyn PeerSelection,
+        max_limit: i64,
+        time_now_ms: i64,
+    ) -> StoreResult<Vec<FamilyPeers>> {
+        let started = Instant::now();
+        let result = self.inner.fetch_peers(info_hash, families, numwant, selection, max_limit, time_now_ms).await;
+        self.metrics.observe_store("fetch_peers", started, result.is_err());
+        return result;
+    }
+
+    async fn fetch_stats(&self, info_hashes: &[RawVal<40>]) -> StoreResult<Vec<TorrentStats>> {
+        let started = Instant::now();
+        let result = self.inner.fetch_stats(info_hashes).await;
+        self.metrics.observe_store("fetch_stats", started, result.is_err());
+        return result;
+    }
+
+    async fn apply(&self, mutations: Vec<Mutation>) -> StoreResult<()> {
+        let started = Instant::now();
+        let result = self.inner.apply(mutations).await;
+        self.metrics.observe_store("apply", started, result.is_err());
+        return result;
+    }
+
+    async fn ping(&self) -> StoreResult<()> {
+        let started = Instant::now();
+        let result = self.inner.ping().await;
+        self.metrics.observe_store("ping", started, result.is_err());
+        return result;
+    }
+
+    async fn fetch_user(&self, key: &str, passkey: &str) -> StoreResult<Option<String>> {
+        let started = Instant::now();
+        let result = self.inner.fetch_user(key, passkey).await;
+        self.metrics.observe_store("fetch_user", started, result.is_err());
+        return result;
+    }
+
+    async fn account(&self, report: private::Report, report_ttl_ms: i64) -> StoreResult<()> {
+        let started = Instant::now();
+        let result = self.inner.account(report, report_ttl_ms).await;
+        self.metrics.observe_store("account", started, result.is_err());
+        return result;
+    }
+
+    async fn fetch_set(&self, key: &str) -> StoreResult<Vec<Vec<u8>>> {
+        let started = Instant::now();
+        let result = self.inner.fetch_set(key).await;
+        self.metrics.observe_store("fetch_set", started, result.is_err());
+        return result;
+    }
+
+    async fn throttle(&self, info_hash: &RawVal<40>, ip_port: &[u8], role: Role, time_now_ms: i64, interval_ms: i64) -> StoreResult<Option<i64>> {
+        let started = Instant::now();
+        let result = self.inner.throttle(info_hash, ip_port, role, time_now_ms, interval_ms).await;
+        self.metrics.observe_store("throttle", started, result.is_err());
+        return result;
+    }
+
+    async fn reap(&self, peer_expiry: i64, torrent_expiry: i64, batch_size: usize) -> StoreResult<ReapStats> {
+        let started = Instant::now();
+        let result = self.inner.reap(peer_expiry, torrent_expiry, batch_size).await;
+        self.metrics.observe_store("reap", started, result.is_err());
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[actix_web::test]
+    async fn renders_observations() {
+        let metrics = Arc::new(Metrics::new());
+        let store = InstrumentedStore::new(Box::new(MemoryStore::new()), metrics.clone());
+
+        metrics.observe_request("http", "announce", Outcome::Ok, Instant::now());
+        metrics.observe_request("udp", "scrape", Outcome::BackendError, Instant::now());
+        metrics.observe_cache(true);
+        store.ping().await.unwrap();
+
+        let rendered = String::from_utf8(metrics.render()).unwrap();
+        assert!(rendered.contains("kiryuu_requests_total{outcome=\"ok\",protocol=\"http\",request=\"announce\"} 1"));
+        assert!(rendered.contains("kiryuu_requests_total{outcome=\"backend_error\",protocol=\"udp\",request=\"scrape\"} 1"));
+        assert!(rendered.contains("kiryuu_request_duration_seconds_count{protocol=\"http\",request=\"announce\"} 1"));
+        assert!(rendered.contains("kiryuu_announce_cache_total{result=\"hit\"} 1"));
+        assert!(rendered.contains("kiryuu_store_duration_seconds_count{op=\"ping\"} 1"));
+        assert!(!rendered.contains("kiryuu_store_errors_total{op=\"ping\"}"));
+    }
+}
@@ -0,0 +1,143 @@
+// Which of a torrent's active peers we hand out on a cache miss.
+// Always giving out the same ones (e.g. the oldest) creates hot spots in big swarms.
+
+use rand::seq::SliceRandom;
+
+use crate::store::Peers;
+
+#[derive(clap::ValueEnum, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Peers that have been in the swarm the longest (the old behaviour)
+    Oldest,
+    /// Peers that announced most recently
+    Freshest,
+    /// A random sample of the swarm. Needs redis >= 6.2 (ZRANDMEMBER)
+    Random,
+}
+
+pub trait PeerSelection: Send + Sync {
+    /// Queue up the one command which picks up to `count` of the active peers
+    /// (score between `max_limit` and `time_now_ms`) in the set at `key`
+    fn queue(&self, pipeline: &mut redis::Pipeline, key: &[u8], count: u16, max_limit: i64, time_now_ms: i64);
+
+    /// Get the peers out of that command's reply
+    fn peers(&self, reply: &redis::Value, count: u16, max_limit: i64) -> redis::RedisResult<Peers>;
+
+    /// The same pick, for stores that keep the swarm in process.
+    /// `active` is every active peer with the time of its last announce.
+    fn pick(&self, active: Vec<(Vec<u8>, i64)>, count: u16) -> Peers;
+}
+
+pub fn new(strategy: Strategy) -> Box<dyn PeerSelection> {
+    return match strategy {
+        Strategy::Oldest => Box::new(Oldest),
+        Strategy::Freshest => Box::new(Freshest),
+        Strategy::Random => Box::new(Random),
+    };
+}
+
+pub struct Oldest;
+
+impl PeerSelection for Oldest {
+    fn queue(&self, pipeline: &mut redis::Pipeline, key: &[u8], count: u16, max_limit: i64, time_now_ms: i64) {
+        pipeline.cmd("ZRANGEBYSCORE").arg(key).arg(max_limit).arg(time_now_ms).arg("LIMIT").arg(0).arg(count);
+    }
+
+    fn peers(&self, reply: &redis::Value, _count: u16, _max_limit: i64) -> redis::RedisResult<Peers> {
+        return redis::from_redis_value(reply);
+    }
+
+    fn pick(&self, mut active: Vec<(Vec<u8>, i64)>, count: u16) -> Peers {
+        active.sort_unstable_by_key(|&(_, last_seen)| last_seen);
+        return active.into_iter().take(count as usize).map(|(peer, _)| peer).collect();
+    }
+}
+
+pub struct Freshest;
+
+impl PeerSelection for Freshest {
+    fn queue(&self, pipeline: &mut redis::Pipeline, key: &[u8], count: u16, max_limit: i64, time_now_ms: i64) {
+        pipeline.cmd("ZREVRANGEBYSCORE").arg(key).arg(time_now_ms).arg(max_limit).arg("LIMIT").arg(0).arg(count);
+    }
+
+    fn peers(&self, reply: &redis::Value, _count: u16, _max_limit: i64) -> redis::RedisResult<Peers> {
+        return redis::from_redis_value(reply);
+    }
+
+    fn pick(&self, mut active: Vec<(Vec<u8>, i64)>, count: u16) -> Peers {
+        active.sort_unstable_by_key(|&(_, last_seen)| std::cmp::Reverse(last_seen));
+        return active.into_iter().take(count as usize).map(|(peer, _)| peer).collect();
+    }
+}
+
+// ZRANDMEMBER can't filter by score, so we ask for a few more than we need
+// and drop the ones that haven't announced within the window
+const RANDOM_OVERSAMPLE: i64 = 2;
+
+pub struct Random;
+
+impl PeerSelection for Random {
+    fn queue(&self, pipeline: &mut redis::Pipeline, key: &[u8], count: u16, _max_limit: i64, _time_now_ms: i64) {
+        // A positive count gives distinct members
+        pipeline.cmd("ZRANDMEMBER").arg(key).arg(count as i64 * RANDOM_OVERSAMPLE).arg("WITHSCORES");
+    }
+
+    fn peers(&self, reply: &redis::Value, count: u16, max_limit: i64) -> redis::RedisResult<Peers> {
+        let sampled: Vec<(Vec<u8>, f64)> = redis::from_redis_value(reply)?;
+
+        return Ok(sampled.into_iter()
+        .filter(|(_, score)| *score >= max_limit as f64)
+        .map(|(peer, _)| peer)
+        .take(count as usize)
+        .collect());
+    }
+
+    fn pick(&self, active: Vec<(Vec<u8>, i64)>, count: u16) -> Peers {
+        return active.choose_multiple(&mut rand::thread_rng(), count as usize).map(|(peer, _)| peer.clone()).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(bytes: &[u8]) -> redis::Value {
+        return redis::Value::Data(bytes.to_vec());
+    }
+
+    #[test]
+    fn range_strategies_return_reply_as_is() {
+        let reply = redis::Value::Bulk(vec![data(b"AAAAAA"), data(b"BBBBBB")]);
+
+        assert_eq!(vec![b"AAAAAA".to_vec(), b"BBBBBB".to_vec()], Oldest.peers(&reply, 2, 0).unwrap());
+        assert_eq!(vec![b"AAAAAA".to_vec(), b"BBBBBB".to_vec()], Freshest.peers(&reply, 2, 0).unwrap());
+    }
+
+    #[test]
+    fn random_drops_stale_peers() {
+        let reply = redis::Value::Bulk(vec![
+            data(b"AAAAAA"), data(b"100"),
+            data(b"BBBBBB"), data(b"5"), // Stale
+            data(b"CCCCCC"), data(b"200"),
+            data(b"DDDDDD"), data(b"300"),
+        ]);
+
+        assert_eq!(vec![b"AAAAAA".to_vec(), b"CCCCCC".to_vec(), b"DDDDDD".to_vec()], Random.peers(&reply, 5, 10).unwrap());
+        assert_eq!(vec![b"AAAAAA".to_vec(), b"CCCCCC".to_vec()], Random.peers(&reply, 2, 10).unwrap());
+        assert_eq!(Vec::<Vec<u8>>::new(), Random.peers(&redis::Value::Bulk(vec![]), 2, 10).unwrap());
+    }
+
+    #[test]
+    fn picks_in_process() {
+        let active = vec![(b"BBBBBB".to_vec(), 200), (b"AAAAAA".to_vec(), 100), (b"CCCCCC".to_vec(), 300)];
+
+        assert_eq!(vec![b"AAAAAA".to_vec(), b"BBBBBB".to_vec()], Oldest.pick(active.clone(), 2));
+        assert_eq!(vec![b"CCCCCC".to_vec(), b"BBBBBB".to_vec()], Freshest.pick(active.clone(), 2));
+
+        let mut picked = Random.pick(active.clone(), 5);
+        picked.sort();
+        assert_eq!(vec![b"AAAAAA".to_vec(), b"BBBBBB".to_vec(), b"CCCCCC".to_vec()], picked);
+        assert_eq!(2, Random.pick(active, 2).len());
+    }
+}
@@ -0,0 +1,203 @@
+// Private mode (`--private`): announces go to `/<passkey>/announce`, and the passkey has to be
+// signed with `--passkey-secret` (see `passkey`), or be in the passkeys hash in redis
+// (passkey => user id). Each announce's upload / download since the peer's previous one
+// is added to the user's totals, overall and per torrent.
+// Clients report what they transferred since they started, so the store keeps each peer's
+// last report (for a peer window), and the difference is what gets counted.
+
+pub mod passkey;
+
+use std::collections::HashSet;
+
+use crate::byte_functions::types::RawVal;
+use crate::query;
+use crate::store::{StoreResult, SwarmStore};
+use crate::swarm;
+
+/// How private mode checks passkeys
+pub struct Passkeys {
+    /// passkey => user id, for passkeys that aren't signed
+    pub redis_hash: String,
+    /// Signs the stateless passkeys
+    pub secret: Option<Vec<u8>>,
+}
+
+impl Passkeys {
+    /// The user id of `passkey`, or why it's refused. Only passkeys that aren't in the signed
+    /// format cost a round trip, so forged ones can't make us hit the store. `revoked` has passkeys and user ids.
+    pub async fn user(&self, store: &dyn SwarmStore, passkey: &str, revoked: &HashSet<String>, time_now_secs: u64) -> StoreResult<Result<String, &'static str>> {
+        if revoked.contains(passkey) {
+            return Ok(Err("Passkey revoked"));
+        }
+
+        let signed = match &self.secret {
+            Some(secret) => passkey::verify(secret, passkey, time_now_secs),
+            None => Err(passkey::PasskeyError::Malformed),
+        };
+
+        let user = match signed {
+            Ok(user) => Some(user),
+            Err(passkey::PasskeyError::Expired) => return Ok(Err("Passkey expired")),
+            Err(passkey::PasskeyError::Invalid) => return Ok(Err("Unknown passkey")),
+            Err(passkey::PasskeyError::Malformed) => store.fetch_user(&self.redis_hash, passkey).await?,
+        };
+
+        return Ok(match user {
+            Some(user) if revoked.contains(&user) => Err("Passkey revoked"),
+            Some(user) => Ok(user),
+            None => Err("Unknown passkey"),
+        });
+    }
+}
+
+/// What a user's peer reported in an announce, see `SwarmStore::account`
+pub struct Report {
+    pub user: String,
+    pub info_hash: RawVal<40>,
+    pub ip_port: Vec<u8>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// The peer's last report, there won't be another one this session
+    pub stopped: bool,
+}
+
+impl Report {
+    pub fn new(user: String, parsed: &query::PeerInfo) -> Report {
+        return Report {
+            user,
+            info_hash: parsed.info_hash,
+            ip_port: swarm::endpoints(parsed).0.ip_port.to_vec(),
+            uploaded: parsed.uploaded,
+            downloaded: parsed.downloaded,
+            stopped: matches!(parsed.event, query::Event::Stopped),
+        };
+    }
+}
+
+/// The (uploaded, downloaded) to count for `report`, given the peer's previous one.
+/// With no previous report (a new session) it all counts, and so does a counter
+/// that went backwards, since the client restarted.
+pub fn transfer_delta(previous: Option<(u64, u64)>, report: &Report) -> (u64, u64) {
+    let delta = |previous: Option<u64>, now: u64| match previous {
+        Some(previous) if now >= previous => now - previous,
+        _ => now,
+    };
+
+    return (delta(previous.map(|p| p.0), report.uploaded), delta(previous.map(|p| p.1), report.downloaded));
+}
+
+/// How the previous report is stored, `uploaded:downloaded`
+pub fn encode_report(report: &Report) -> String {
+    return format!("{}:{}", report.uploaded, report.downloaded);
+}
+
+pub fn decode_report(encoded: &str) -> Option<(u64, u64)> {
+    let (uploaded, downloaded) = encoded.split_once(':')?;
+    return Some((uploaded.parse().ok()?, downloaded.parse().ok()?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    use crate::peer_selection::PeerSelection;
+    use crate::store::{self, MemoryStore};
+
+    fn report(uploaded: u64, downloaded: u64) -> Report {
+        return Report { user: "1".to_string(), info_hash: RawVal(*b"41aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"), ip_port: b"AAAAAA".to_vec(), uploaded, downloaded, stopped: false };
+    }
+
+    #[test]
+    fn counts_what_changed() {
+        assert_eq!((100, 50), transfer_delta(None, &report(100, 50)));
+        assert_eq!((20, 0), transfer_delta(Some((100, 50)), &report(120, 50)));
+        // Restarted, so the counters started over
+        assert_eq!((10, 50), transfer_delta(Some((100, 50)), &report(10, 100)));
+    }
+
+    #[actix_web::test]
+    async fn checks_passkeys() {
+        let store = MemoryStore::new();
+        let passkeys = Passkeys { redis_hash: "kiryuu_passkeys".to_string(), secret: Some(b"hunter2".to_vec()) };
+        let minted = passkey::mint(b"hunter2", "42", Some(2000)).unwrap();
+        let revoked = HashSet::from(["7".to_string()]);
+
+        assert_eq!(Ok("42".to_string()), passkeys.user(&store, &minted, &revoked, 1000).await.unwrap());
+        assert_eq!(Err("Passkey expired"), passkeys.user(&store, &minted, &revoked, 2000).await.unwrap());
+        // Neither signed nor in the (memory store's empty) hash
+        assert_eq!(Err("Unknown passkey"), passkeys.user(&store, "8f2b1c0e", &revoked, 1000).await.unwrap());
+
+        let revoked_user = passkey::mint(b"hunter2", "7", None).unwrap();
+        assert_eq!(Err("Passkey revoked"), passkeys.user(&store, &revoked_user, &revoked, 1000).await.unwrap());
+        assert_eq!(Err("Passkey revoked"), passkeys.user(&store, &minted, &HashSet::from([minted.clone()]), 1000).await.unwrap());
+    }
+
+    /// For what mustn't touch the store
+    struct NoStore;
+
+    // Every method panics, which async_trait wraps in a future
+    #[allow(clippy::diverging_sub_expression)]
+    #[async_trait(?Send)]
+    impl SwarmStore for NoStore {
+        async fn announce(&self, _: &query::PeerInfo, _: u16, _: i64) -> StoreResult<store::Announced> {
+            panic!("announce");
+        }
+
+        async fn fetch_peers(&self, _: &RawVal<40>, _: &[store::Family], _: u16, _: &dyn PeerSelection, _: i64, _: i64) -> StoreResult<Vec<store::FamilyPeers>> {
+            panic!("fetch_peers");
+        }
+
+        async fn fetch_stats(&self, _: &[RawVal<40>]) -> StoreResult<Vec<store::TorrentStats>> {
+            panic!("fetch_stats");
+        }
+
+        async fn apply(&self, _: Vec<store::Mutation>) -> StoreResult<()> {
+            panic!("apply");
+        }
+
+        async fn ping(&self) -> StoreResult<()> {
+            panic!("ping");
+        }
+
+        async fn fetch_user(&self, _: &str, passkey: &str) -> StoreResult<Option<String>> {
+            panic!("fetch_user {}", passkey);
+        }
+
+        async fn account(&self, _: Report, _: i64) -> StoreResult<()> {
+            panic!("account");
+        }
+
+        async fn fetch_set(&self, _: &str) -> StoreResult<Vec<Vec<u8>>> {
+            panic!("fetch_set");
+        }
+
+        async fn throttle(&self, _: &RawVal<40>, _: &[u8], _: store::Role, _: i64, _: i64) -> StoreResult<Option<i64>> {
+            panic!("throttle");
+        }
+
+        async fn reap(&self, _: i64, _: i64, _: usize) -> StoreResult<store::ReapStats> {
+            panic!("reap");
+        }
+    }
+
+    #[actix_web::test]
+    async fn refuses_forged_passkeys_without_the_store() {
+        let passkeys = Passkeys { redis_hash: "kiryuu_passkeys".to_string(), secret: Some(b"hunter2".to_vec()) };
+        let minted = passkey::mint(b"hunter2", "42", Some(2000)).unwrap();
+        let forged = passkey::mint(b"hunter3", "42", None).unwrap();
+        let revoked = HashSet::new();
+
+        assert_eq!(Ok("42".to_string()), passkeys.user(&NoStore, &minted, &revoked, 1000).await.unwrap());
+        assert_eq!(Err("Passkey expired"), passkeys.user(&NoStore, &minted, &revoked, 2000).await.unwrap());
+        assert_eq!(Err("Unknown passkey"), passkeys.user(&NoStore, &forged, &revoked, 1000).await.unwrap());
+        assert_eq!(Err("Unknown passkey"), passkeys.user(&NoStore, &minted.replacen("42.", "43.", 1), &revoked, 1000).await.unwrap());
+    }
+
+    #[test]
+    fn roundtrips_reports() {
+        assert_eq!(Some((120, 50)), decode_report(&encode_report(&report(120, 50))));
+        assert_eq!(None, decode_report("120"));
+        assert_eq!(None, decode_report("a:1"));
+    }
+}
@@ -0,0 +1,106 @@
+// Stateless passkeys, `<user id>.<expiry>.<signature>`: the expiry is in unix seconds (0 for never),
+// and the signature is an HMAC-SHA256 of the rest with `--passkey-secret`, cut to 16 bytes (in hex).
+// Checking one doesn't need the store. Mint them with `kiryuu mint-passkey`.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNATURE_BYTES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum PasskeyError {
+    /// Not `<user id>.<expiry>.<signature>`, so not one of ours
+    Malformed,
+    /// Looks like one of ours, but isn't signed with the secret (tampered with, or forged)
+    Invalid,
+    Expired,
+}
+
+fn signature(secret: &[u8], user: &str, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(format!("{}.{}", user, expires).as_bytes());
+    return mac;
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    return (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect();
+}
+
+/// The user ids we sign have to stay readable (and URL safe) in the passkey
+fn is_valid_user(user: &str) -> bool {
+    return !user.is_empty() && user.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-');
+}
+
+/// A passkey for `user`, good until `expires` (unix seconds), or forever
+pub fn mint(secret: &[u8], user: &str, expires: Option<u64>) -> Result<String, String> {
+    if !is_valid_user(user) {
+        return Err("user ids can only have letters, digits, _ and -".to_string());
+    }
+
+    let expires = expires.unwrap_or(0);
+    let signature: String = signature(secret, user, expires).finalize().into_bytes()[..SIGNATURE_BYTES].iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    return Ok(format!("{}.{}.{}", user, expires, signature));
+}
+
+/// The user id `passkey` was minted for, if it's signed with `secret` and hasn't expired
+pub fn verify(secret: &[u8], passkey: &str, time_now_secs: u64) -> Result<String, PasskeyError> {
+    let mut parts = passkey.split('.');
+    let (user, expires, signature_hex) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(user), Some(expires), Some(signature), None) => (user, expires, signature),
+        _ => return Err(PasskeyError::Malformed),
+    };
+
+    let expires: u64 = expires.parse().map_err(|_| PasskeyError::Malformed)?;
+    let claimed = decode_hex(signature_hex).filter(|claimed| claimed.len() == SIGNATURE_BYTES).ok_or(PasskeyError::Malformed)?;
+
+    // Constant time, so the signature can't be guessed byte by byte
+    signature(secret, user, expires).verify_truncated_left(&claimed).map_err(|_| PasskeyError::Invalid)?;
+
+    if expires != 0 && expires <= time_now_secs {
+        return Err(PasskeyError::Expired);
+    }
+
+    return Ok(user.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"hunter2";
+
+    #[test]
+    fn verifies_what_it_mints() {
+        let forever = mint(SECRET, "42", None).unwrap();
+        assert!(forever.starts_with("42.0."));
+        assert_eq!(Ok("42".to_string()), verify(SECRET, &forever, 1_000_000));
+
+        let until = mint(SECRET, "user_7", Some(2000)).unwrap();
+        assert_eq!(Ok("user_7".to_string()), verify(SECRET, &until, 1999));
+        assert_eq!(Err(PasskeyError::Expired), verify(SECRET, &until, 2000));
+
+        assert!(mint(SECRET, "4.2", None).is_err());
+        assert!(mint(SECRET, "", None).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let passkey = mint(SECRET, "42", Some(2000)).unwrap();
+        let signature = passkey.rsplit('.').next().unwrap();
+
+        assert_eq!(Err(PasskeyError::Invalid), verify(b"hunter3", &passkey, 1000));
+        assert_eq!(Err(PasskeyError::Invalid), verify(SECRET, &format!("43.2000.{}", signature), 1000));
+        assert_eq!(Err(PasskeyError::Invalid), verify(SECRET, &format!("42.0.{}", signature), 1000));
+        assert_eq!(Err(PasskeyError::Malformed), verify(SECRET, &format!("42.2000.{}", &signature[..30]), 1000));
+        assert_eq!(Err(PasskeyError::Malformed), verify(SECRET, "8f2b1c0e", 1000));
+        assert_eq!(Err(PasskeyError::Malformed), verify(SECRET, &format!("{}.extra", passkey), 1000));
+        assert_eq!(Err(PasskeyError::Malformed), verify(SECRET, &format!("42.soon.{}", signature), 1000));
+    }
+}
@@ -15,25 +15,82 @@ pub struct AReq {
     /// non-zero left - leecher
     left: String,
 
+    /// Bytes sent / received this session, only used in private mode (see `private`).
+    /// Kept as strings like `numwant`, junk counts as 0
+    pub uploaded: Option<String>,
+    pub downloaded: Option<String>,
+
     pub event: Option<String>,
+
+    /// How many peers the client would like. Kept as a string so junk
+    /// here doesn't fail the whole announce, we just fall back to the default
+    pub numwant: Option<String>,
+
+    /// BEP 7: A dual stack client can tell us its address in the other family,
+    /// so it can be handed out in both `peers` and `peers6`
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
 }
 
+#[derive(Debug)]
 pub enum Event {
     Unknown,
     Stopped,
     Completed,
+    /// A new session, counted in the stats but otherwise a regular announce
+    Started,
+    /// BEP 21: a partial seed, which has all it wants and won't download any more
+    Paused,
 }
 
+/// At least one of `ip_port` / `ip6_port` is always set,
+/// the one of the address the announce came from
 pub struct PeerInfo {
-    pub ip_port: [u8; 6],
+    pub ip_port: Option<[u8; 6]>,
+    pub ip6_port: Option<[u8; 18]>,
     pub info_hash: byte_functions::types::RawVal<40>,
     pub is_seeding: bool,
-    pub event: Event
+    pub event: Event,
+    pub numwant: Option<u32>,
+    /// Bytes the client sent / received since it started, as it reports them
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl PeerInfo {
+    /// Announced `paused` with something left, see `Event::Paused`
+    pub fn is_partial_seed(&self) -> bool {
+        return matches!(self.event, Event::Paused) && !self.is_seeding;
+    }
+
+    /// Seeders & partial seeds, which have no use for peers that aren't leechers
+    pub fn is_done(&self) -> bool {
+        return self.is_seeding || self.is_partial_seed();
+    }
 }
 
 pub enum QueryError {
     ParseFailure,
     InvalidInfohash,
+    MissingInfohash,
+}
+
+impl QueryError {
+    /// What we tell the client, as the `failure reason`
+    pub fn reason(&self) -> &'static str {
+        match self {
+            QueryError::ParseFailure => "Failed to parse announce",
+            QueryError::InvalidInfohash => "Infohash is not 20 bytes",
+            QueryError::MissingInfohash => "Missing info_hash",
+        }
+    }
+}
+
+/// BEP 31: When the client should try again after a failure
+#[derive(Debug, PartialEq)]
+pub enum RetryIn {
+    Minutes(u32),
+    Never,
 }
 
 // Allows us to use `?` postfix and wrap to QueryError
@@ -43,50 +100,294 @@ impl From<serde_qs::Error> for QueryError {
     }
 }
 
-pub fn parse_announce(ip_addr: &std::net::Ipv4Addr, query: &[u8]) -> Result<PeerInfo, QueryError> {
-    let parsed: AReq = qs::from_bytes(query)?;
-    let hex_str_info_hash = byte_functions::url_encoded_to_hex_u8(&parsed.info_hash);
+// The query string is %-escaped again before we parse it (to keep the infohash bytes intact),
+// so the `ipv4` / `ipv6` params still need to be decoded
+fn percent_decode(encoded: &str) -> Option<String> {
+    let raw = encoded.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(raw.len());
+    let mut pos = 0;
+
+    while pos < raw.len() {
+        if raw[pos] == b'%' {
+            let hex = std::str::from_utf8(raw.get(pos+1..pos+3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            pos += 3;
+        } else {
+            decoded.push(raw[pos]);
+            pos += 1;
+        }
+    }
+
+    return String::from_utf8(decoded).ok();
+}
 
-    if hex_str_info_hash.len() != 40 {
-        return Err(QueryError::InvalidInfohash);
+// BEP 7 allows either a bare address, or address:port
+fn parse_ipv4_param(param: &str, port: u16) -> Option<[u8; 6]> {
+    let decoded = percent_decode(param)?;
+
+    if let Ok(addr) = decoded.parse::<std::net::SocketAddrV4>() {
+        return Some(byte_functions::ip_str_port_u16_to_bytes(addr.ip(), addr.port()));
+    }
+
+    let addr = decoded.parse::<std::net::Ipv4Addr>().ok()?;
+    return Some(byte_functions::ip_str_port_u16_to_bytes(&addr, port));
+}
+
+fn parse_ipv6_param(param: &str, port: u16) -> Option<[u8; 18]> {
+    let decoded = percent_decode(param)?;
+
+    if let Ok(addr) = decoded.parse::<std::net::SocketAddrV6>() {
+        return Some(byte_functions::ip6_port_u16_to_bytes(addr.ip(), addr.port()));
     }
 
-    let is_seeding = match parsed.left.as_str() {
-        "0" => true,
-        _ => false,
+    let addr = decoded.parse::<std::net::Ipv6Addr>().ok()?;
+    return Some(byte_functions::ip6_port_u16_to_bytes(&addr, port));
+}
+
+/// `ip_addr` is the address the announce came from. It is always used for its own family,
+/// the `ipv4` / `ipv6` params are only taken for the other family, so peers can't
+/// spoof the address they announce from.
+pub fn parse_announce(ip_addr: &std::net::IpAddr, query: &[u8]) -> Result<PeerInfo, QueryError> {
+    let parsed: AReq = qs::from_bytes(query)?;
+    let hex_str_info_hash = match byte_functions::url_encoded_to_hex_u8_checked(&parsed.info_hash) {
+        Some(hex_str_info_hash) => hex_str_info_hash,
+        None => return Err(QueryError::InvalidInfohash),
     };
 
+    let is_seeding = matches!(parsed.left.as_str(), "0");
+
     let announce_event = if let Some(ref event) = parsed.event {
         match event.as_str() {
             "stopped" => Event::Stopped,
             "completed" => Event::Completed,
+            "started" => Event::Started,
+            "paused" => Event::Paused,
             _ => Event::Unknown,
         }
     } else {
         Event::Unknown
     };
 
+    let (ip_port, ip6_port) = match ip_addr.to_canonical() {
+        std::net::IpAddr::V4(v4_addr) => (
+            Some(byte_functions::ip_str_port_u16_to_bytes(&v4_addr, parsed.port)),
+            parsed.ipv6.as_deref().and_then(|param| parse_ipv6_param(param, parsed.port)),
+        ),
+        std::net::IpAddr::V6(v6_addr) => (
+            parsed.ipv4.as_deref().and_then(|param| parse_ipv4_param(param, parsed.port)),
+            Some(byte_functions::ip6_port_u16_to_bytes(&v6_addr, parsed.port)),
+        ),
+    };
+
     return Ok(PeerInfo{
-        ip_port: byte_functions::ip_str_port_u16_to_bytes(ip_addr, parsed.port),
+        ip_port,
+        ip6_port,
         info_hash: byte_functions::types::RawVal(hex_str_info_hash),
         is_seeding,
         event: announce_event,
+        numwant: parsed.numwant.as_deref().and_then(|numwant| numwant.parse().ok()),
+        uploaded: parsed.uploaded.as_deref().and_then(|uploaded| uploaded.parse().ok()).unwrap_or(0),
+        downloaded: parsed.downloaded.as_deref().and_then(|downloaded| downloaded.parse().ok()).unwrap_or(0),
     });
 }
 
-pub fn announce_reply(seeders_count: i64, leechers_count: i64, seeders: &[Vec<u8>], leechers: &[Vec<u8>]) -> Vec<u8> {
-    // This is the number of peers in the response, not total peer count
-    let peers_length = seeders.len() + leechers.len();
+/// A torrent in a scrape reply, see `scrape_reply`
+pub type ScrapeFile = ([u8; 20], i64, i64, i64, i64);
+
+/// A scrape can have the `info_hash` param multiple times, which serde_qs won't give us,
+/// so pick them out of the (raw, still %-encoded) query string ourselves.
+pub fn parse_scrape(query: &str) -> Result<Vec<byte_functions::types::RawVal<40>>, QueryError> {
+    let mut info_hashes = Vec::new();
+
+    for param in query.split('&') {
+        if let Some(urlenc) = param.strip_prefix("info_hash=") {
+            match byte_functions::url_encoded_to_hex_u8_checked(urlenc) {
+                Some(hex_str_info_hash) => info_hashes.push(byte_functions::types::RawVal(hex_str_info_hash)),
+                None => return Err(QueryError::InvalidInfohash),
+            }
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return Err(QueryError::MissingInfohash);
+    }
+
+    return Ok(info_hashes);
+}
+
+/// `files` is (raw infohash, complete, downloaded, incomplete, downloaders) for each torrent.
+/// `downloaders` (BEP 21) are the incomplete peers that aren't partial seeds.
+/// Bencoded dict keys need to be sorted (and unique), so we take care of that here.
+pub fn scrape_reply(files: &[ScrapeFile]) -> Vec<u8> {
+    let sorted: std::collections::BTreeMap<&[u8; 20], (i64, i64, i64, i64)> = files.iter().map(|(info_hash, complete, downloaded, incomplete, downloaders)| (info_hash, (*complete, *downloaded, *incomplete, *downloaders))).collect();
+
+    let mut response_body: Vec<u8> = b"d5:filesd".to_vec();
+
+    for (info_hash, (complete, downloaded, incomplete, downloaders)) in sorted {
+        response_body.extend_from_slice(b"20:");
+        response_body.extend_from_slice(info_hash);
+        response_body.extend_from_slice(("d8:completei".to_string()
+        + &complete.to_string()
+        + "e10:downloadedi"
+        + &downloaded.to_string()
+        + "e11:downloadersi"
+        + &downloaders.to_string()
+        + "e10:incompletei"
+        + &incomplete.to_string()
+        + "ee").as_bytes());
+    }
+
+    response_body.extend_from_slice(b"ee");
+    return response_body;
+}
+
+/// A bencoded `failure reason`, which clients can actually show to the user
+/// (unlike a plaintext HTTP error), optionally with a BEP 31 `retry in`
+pub fn failure_reply(reason: &str, retry_in: Option<RetryIn>) -> Vec<u8> {
+    let retry_in_string = match retry_in {
+        Some(RetryIn::Minutes(minutes)) => "8:retry ini".to_string() + &minutes.to_string() + "e",
+        Some(RetryIn::Never) => "8:retry in5:never".to_string(),
+        None => "".to_string(),
+    };
+
+    let response_body_string = "d14:failure reason".to_string()
+    + &reason.len().to_string()
+    + ":"
+    + reason
+    + &retry_in_string
+    + "e";
+
+    return response_body_string.into_bytes();
+}
+
+/// How many of the `numwant` peers to give a leecher from the seeders & leechers.
+/// Half each, with whatever one side can't fill going to the other.
+pub fn split_numwant(numwant: u16, seeders_available: usize, leechers_available: usize) -> (usize, usize) {
+    let numwant = numwant as usize;
+    let seeders_take = std::cmp::min(seeders_available, numwant - numwant / 2);
+    let leechers_take = std::cmp::min(leechers_available, numwant - seeders_take);
+    let seeders_take = std::cmp::min(seeders_available, numwant - leechers_take);
+
+    return (seeders_take, leechers_take);
+}
+
+/// Pick up to `numwant` of the compact `seeders` / `leechers` (`width` bytes each) for a peer,
+/// leaving out the peer itself (`exclude`).
+/// A seeder (or partial seed, see `PeerInfo::is_done`) has no use for other seeders, so it only gets leechers.
+/// A leecher gets a mix of both, as per `split_numwant`.
+pub fn pick_peers(seeders: &[u8], leechers: &[u8], width: usize, exclude: Option<&[u8]>, is_done: bool, numwant: u16) -> Vec<u8> {
+    let seeders = compact_without(seeders, width, exclude);
+    let leechers = compact_without(leechers, width, exclude);
+
+    let (seeders_take, leechers_take) = if is_done {
+        (0, std::cmp::min(leechers.len() / width, numwant as usize))
+    } else {
+        split_numwant(numwant, seeders.len() / width, leechers.len() / width)
+    };
+
+    return [&seeders[..seeders_take * width], &leechers[..leechers_take * width]].concat();
+}
+
+/// What we cache for a torrent: the counts, and the compact peers we picked (back to back,
+/// 6 bytes each for IPv4, 18 for IPv6), up to `numwant` each of seeders & leechers.
+/// Every reply is built from this, so we can cheaply tailor it to the peer that is asking.
+/// Partial seeds are in with the seeders: only leechers get them, and they're `incomplete`.
+pub struct CachedPeers {
+    pub seeders_count: i64,
+    /// Leechers & partial seeds
+    pub leechers_count: i64,
+    pub seeders: Vec<u8>,
+    pub leechers: Vec<u8>,
+    pub seeders6: Vec<u8>,
+    pub leechers6: Vec<u8>,
+}
+
+// The compact peers in `peers`, except `exclude`
+fn compact_without(peers: &[u8], width: usize, exclude: Option<&[u8]>) -> Vec<u8> {
+    return peers.chunks_exact(width).filter(|&peer| Some(peer) != exclude).flatten().copied().collect();
+}
+
+impl CachedPeers {
+    /// The announce reply for `peer`, with up to `numwant` peers (of each family) as per `pick_peers`.
+    /// The count mods are the changes this announce makes, which the cached counts don't have yet.
+    pub fn reply(&self, peer: &PeerInfo, numwant: u16, seed_count_mod: i64, leech_count_mod: i64, interval: u32, min_interval: u32) -> Vec<u8> {
+        let ip_port = peer.ip_port.as_ref().map(|ip_port| &ip_port[..]);
+        let ip6_port = peer.ip6_port.as_ref().map(|ip6_port| &ip6_port[..]);
+
+        let peers = pick_peers(&self.seeders, &self.leechers, 6, ip_port, peer.is_done(), numwant);
+        let peers6 = pick_peers(&self.seeders6, &self.leechers6, 18, ip6_port, peer.is_done(), numwant);
+
+        return announce_reply(self.seeders_count + seed_count_mod, self.leechers_count + leech_count_mod, interval, min_interval, &peers, &peers6);
+    }
+
+    /// The counts (8 bytes each), then each list of peers prefixed with its length (4 bytes)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(32 + self.seeders.len() + self.leechers.len() + self.seeders6.len() + self.leechers6.len());
+        encoded.extend_from_slice(&self.seeders_count.to_be_bytes());
+        encoded.extend_from_slice(&self.leechers_count.to_be_bytes());
+
+        for peers in [&self.seeders, &self.leechers, &self.seeders6, &self.leechers6] {
+            encoded.extend_from_slice(&(peers.len() as u32).to_be_bytes());
+            encoded.extend_from_slice(peers);
+        }
+
+        return encoded;
+    }
+
+    /// None if `encoded` isn't something `encode` made (e.g. a cache miss)
+    pub fn decode(encoded: &[u8]) -> Option<CachedPeers> {
+        let seeders_count = i64::from_be_bytes(encoded.get(0..8)?.try_into().ok()?);
+        let leechers_count = i64::from_be_bytes(encoded.get(8..16)?.try_into().ok()?);
+
+        let mut pos = 16;
+        let mut lists: Vec<Vec<u8>> = Vec::with_capacity(4);
+
+        for width in [6, 6, 18, 18] {
+            let len = u32::from_be_bytes(encoded.get(pos..pos+4)?.try_into().ok()?) as usize;
+            let peers = encoded.get(pos+4..pos+4+len)?;
+
+            if !len.is_multiple_of(width) {
+                return None;
+            }
+
+            lists.push(peers.to_vec());
+            pos += 4 + len;
+        }
+
+        if pos != encoded.len() {
+            return None;
+        }
+
+        let leechers6 = lists.pop()?;
+        let seeders6 = lists.pop()?;
+        let leechers = lists.pop()?;
+        let seeders = lists.pop()?;
+
+        return Some(CachedPeers { seeders_count, leechers_count, seeders, leechers, seeders6, leechers6 });
+    }
+}
 
+/// `peers` / `peers6` are the compact peers back to back, 6 / 18 bytes each
+/// `min_interval` is the soonest the client may announce again, see `rate_limit`
+pub fn announce_reply(seeders_count: i64, leechers_count: i64, interval: u32, min_interval: u32, peers: &[u8], peers6: &[u8]) -> Vec<u8> {
     let response_body_string = "d8:completei".to_string() 
     + &seeders_count.to_string()
-    + &"e10:incompletei".to_string() 
+    + "e10:incompletei"
     + &leechers_count.to_string()
-    + &"e8:intervali1800e12:min intervali1800e5:peers".to_string()
-    + &(peers_length * 6).to_string()
-    + &":";
+    + "e8:intervali"
+    + &interval.to_string()
+    + "e12:min intervali"
+    + &min_interval.to_string()
+    + "e5:peers"
+    + &peers.len().to_string()
+    + ":";
+
+    // BEP 7: IPv6 peers go in their own key
+    let peers6_string = "6:peers6".to_string()
+    + &peers6.len().to_string()
+    + ":";
 
-    let response_body: Vec<u8> = [response_body_string.into_bytes(), seeders.concat(), leechers.concat(), "e".as_bytes().to_vec()].concat() ;
+    let response_body: Vec<u8> = [response_body_string.into_bytes(), peers.to_vec(), peers6_string.into_bytes(), peers6.to_vec(), "e".as_bytes().to_vec()].concat() ;
 
     return response_body;
 }
@@ -96,18 +397,225 @@ mod tests {
     use super::*;
 
     #[test]
-    fn is_legit(){
-        let bytes: Vec<u8> = Vec::from([1, 2, 3, 4]);
-        let _no_bytes: Vec<u8> = Vec::from([]);
-    
-        let mut p1: Vec<Vec<u8>> = Vec::new();
-        p1.push(bytes);
-        
-        let p2: Vec<Vec<u8>> = Vec::new();
-        // p2.push(no_bytes);
-    
-        // TODO: Actually implement a test here...
-        let gg = announce_reply(1, 2, &p1, &p2);
-        println!("GG is {:?}", gg);
-    }
-}
\ No newline at end of file
+    fn announce_reply_is_bencoded() {
+        let reply = announce_reply(1, 2, 1800, 900, b"AAAAAA", &[]);
+        assert_eq!(b"d8:completei1e10:incompletei2e8:intervali1800e12:min intervali900e5:peers6:AAAAAA6:peers60:e".to_vec(), reply);
+
+        let reply = announce_reply(0, 0, 1800, 1800, &[], b"BBBBBBBBBBBBBBBBBB");
+        assert_eq!(b"d8:completei0e10:incompletei0e8:intervali1800e12:min intervali1800e5:peers0:6:peers618:BBBBBBBBBBBBBBBBBBe".to_vec(), reply);
+    }
+
+    fn parse(ip: &str, query: &str) -> PeerInfo {
+        let ip_addr: std::net::IpAddr = ip.parse().unwrap();
+        return match parse_announce(&ip_addr, query.replace("%", "%25").as_bytes()) {
+            Ok(parsed) => parsed,
+            Err(_) => panic!("Failed to parse {}", query),
+        };
+    }
+
+    #[test]
+    fn parses_ipv4_and_ipv6() {
+        let v4 = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0");
+        assert_eq!(Some([127, 0, 0, 1, 13, 5]), v4.ip_port);
+        assert_eq!(None, v4.ip6_port);
+
+        let v6 = parse("::1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0");
+        assert_eq!(None, v6.ip_port);
+        assert_eq!(Some([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 13, 5]), v6.ip6_port);
+
+        // IPv4 mapped addresses (dual stack sockets) are treated as IPv4
+        let mapped = parse("::ffff:127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0");
+        assert_eq!(Some([127, 0, 0, 1, 13, 5]), mapped.ip_port);
+        assert_eq!(None, mapped.ip6_port);
+    }
+
+    #[test]
+    fn parses_transfer() {
+        let parsed = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&uploaded=1000&downloaded=20");
+        assert_eq!((1000, 20), (parsed.uploaded, parsed.downloaded));
+
+        let junk = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&uploaded=-1");
+        assert_eq!((0, 0), (junk.uploaded, junk.downloaded));
+    }
+
+    #[test]
+    fn parses_events() {
+        let started = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10&event=started");
+        assert!(matches!(started.event, Event::Started));
+        assert!(!started.is_done());
+
+        let paused = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10&event=paused");
+        assert!(paused.is_partial_seed());
+        assert!(paused.is_done());
+
+        // Nothing left, that's just a seeder
+        let paused_seeder = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&event=paused");
+        assert!(!paused_seeder.is_partial_seed());
+        assert!(paused_seeder.is_done());
+
+        let junk = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10&event=nope");
+        assert!(matches!(junk.event, Event::Unknown));
+    }
+
+    #[test]
+    fn parses_dual_stack_params() {
+        let v4 = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&ipv6=%3A%3A1");
+        assert_eq!(Some([127, 0, 0, 1, 13, 5]), v4.ip_port);
+        assert_eq!(Some([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 13, 5]), v4.ip6_port);
+
+        let v4_with_port = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&ipv6=%5B%3A%3A1%5D%3A3334");
+        assert_eq!(Some([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 13, 6]), v4_with_port.ip6_port);
+
+        let v6 = parse("::1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&ipv4=1.1.1.1%3A3334");
+        assert_eq!(Some([1, 1, 1, 1, 13, 6]), v6.ip_port);
+
+        // Can't override the address of the family the announce came from
+        let spoofed = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&ipv4=1.1.1.1");
+        assert_eq!(Some([127, 0, 0, 1, 13, 5]), spoofed.ip_port);
+
+        let garbage = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&ipv6=lol");
+        assert_eq!(None, garbage.ip6_port);
+    }
+
+    #[test]
+    fn announce_reply_has_peers6() {
+        let reply = announce_reply(2, 0, 1800, 1800, &[1, 2, 3, 4, 5, 6], &[0; 18]);
+        let expected = [
+            b"d8:completei2e10:incompletei0e8:intervali1800e12:min intervali1800e5:peers6:".to_vec(),
+            vec![1, 2, 3, 4, 5, 6],
+            b"6:peers618:".to_vec(),
+            vec![0; 18],
+            b"e".to_vec(),
+        ].concat();
+
+        assert_eq!(expected, reply);
+    }
+
+    #[test]
+    fn parses_numwant() {
+        assert_eq!(None, parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0").numwant);
+        assert_eq!(Some(0), parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&numwant=0").numwant);
+        assert_eq!(Some(200), parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&numwant=200").numwant);
+        assert_eq!(None, parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0&numwant=-1").numwant);
+    }
+
+    #[test]
+    fn rejects_bad_infohash() {
+        let ip_addr: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        let too_long = "info_hash=AAAAAAAAAAAAAAAAAAAAA&port=3333&left=0";
+        assert!(matches!(parse_announce(&ip_addr, too_long.as_bytes()), Err(QueryError::InvalidInfohash)));
+    }
+
+    #[test]
+    fn parses_scrape() {
+        let info_hashes = match parse_scrape("info_hash=AAAAAAAAAAAAAAAAAAAA&foo=bar&info_hash=%DD%00%D2%1CuDA%AAL%B6J%1E%A7z%2CvFAR%C3") {
+            Ok(info_hashes) => info_hashes,
+            Err(_) => panic!("Failed to parse scrape"),
+        };
+
+        assert_eq!(2, info_hashes.len());
+        assert_eq!(*b"4141414141414141414141414141414141414141", info_hashes[0].0);
+        assert_eq!(*b"dd00d21c754441aa4cb64a1ea77a2c76464152c3", info_hashes[1].0);
+
+        assert!(matches!(parse_scrape("foo=bar"), Err(QueryError::MissingInfohash)));
+        assert!(matches!(parse_scrape("info_hash=AAAA"), Err(QueryError::InvalidInfohash)));
+    }
+
+    #[test]
+    fn failure_reply_is_bencoded() {
+        assert_eq!(b"d14:failure reason24:Infohash is not 20 bytese".to_vec(), failure_reply(QueryError::InvalidInfohash.reason(), None));
+        assert_eq!(b"d14:failure reason4:nope8:retry ini5ee".to_vec(), failure_reply("nope", Some(RetryIn::Minutes(5))));
+        assert_eq!(b"d14:failure reason4:nope8:retry in5:nevere".to_vec(), failure_reply("nope", Some(RetryIn::Never)));
+    }
+
+    #[test]
+    fn scrape_reply_is_sorted() {
+        let reply = scrape_reply(&[([0x42; 20], 1, 2, 3, 3), ([0x41; 20], 4, 5, 6, 2)]);
+        let expected = "d5:filesd20:AAAAAAAAAAAAAAAAAAAAd8:completei4e10:downloadedi5e11:downloadersi2e10:incompletei6ee20:BBBBBBBBBBBBBBBBBBBBd8:completei1e10:downloadedi2e11:downloadersi3e10:incompletei3eeee";
+        assert_eq!(expected.as_bytes(), reply);
+    }
+
+    fn cached_peers() -> CachedPeers {
+        return CachedPeers {
+            seeders_count: 2,
+            leechers_count: 1,
+            seeders: vec![127, 0, 0, 1, 13, 5, 1, 1, 1, 1, 0, 80],
+            leechers: vec![2, 2, 2, 2, 0, 80],
+            seeders6: vec![],
+            leechers6: [vec![0; 15], vec![1, 13, 5]].concat(),
+        };
+    }
+
+    #[test]
+    fn cached_peers_roundtrip() {
+        let cached = cached_peers();
+        let decoded = CachedPeers::decode(&cached.encode()).unwrap();
+
+        assert_eq!(cached.seeders_count, decoded.seeders_count);
+        assert_eq!(cached.leechers_count, decoded.leechers_count);
+        assert_eq!(cached.seeders, decoded.seeders);
+        assert_eq!(cached.leechers, decoded.leechers);
+        assert_eq!(cached.seeders6, decoded.seeders6);
+        assert_eq!(cached.leechers6, decoded.leechers6);
+
+        let encoded = cached.encode();
+        assert!(CachedPeers::decode(&[]).is_none());
+        assert!(CachedPeers::decode(&encoded[..encoded.len() - 1]).is_none());
+        assert!(CachedPeers::decode(&[encoded.clone(), vec![0]].concat()).is_none());
+        assert!(CachedPeers::decode(b"d8:completei1e10:incompletei0e8:intervali1800e12:min intervali1800e5:peers0:e").is_none());
+    }
+
+    #[test]
+    fn cached_reply_excludes_requester() {
+        let cached = cached_peers();
+
+        // Someone else gets everyone
+        let other = parse("8.8.8.8", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10");
+        assert_eq!(announce_reply(2, 1, 1800, 1800, &[cached.seeders.clone(), cached.leechers.clone()].concat(), &cached.leechers6), cached.reply(&other, 50, 0, 0, 1800, 1800));
+
+        // The requesting peer is left out, of both peers & peers6
+        let me = parse("127.0.0.1", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10&ipv6=%3A%3A1");
+        assert_eq!(announce_reply(3, 0, 1800, 1800, &[1, 1, 1, 1, 0, 80, 2, 2, 2, 2, 0, 80], &[]), cached.reply(&me, 50, 1, -1, 1800, 1800));
+    }
+
+    #[test]
+    fn cached_reply_is_role_aware() {
+        let cached = cached_peers();
+
+        // Seeders only get leechers
+        let seeder = parse("8.8.8.8", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=0");
+        assert_eq!(announce_reply(2, 1, 1800, 1800, &cached.leechers, &cached.leechers6), cached.reply(&seeder, 50, 0, 0, 1800, 1800));
+
+        // Leechers get a mix, within numwant
+        let leecher = parse("8.8.8.8", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10");
+        assert_eq!(announce_reply(2, 1, 1800, 1800, &[127, 0, 0, 1, 13, 5, 2, 2, 2, 2, 0, 80], &cached.leechers6), cached.reply(&leecher, 2, 0, 0, 1800, 1800));
+        assert_eq!(announce_reply(2, 1, 1800, 1800, &[], &[]), cached.reply(&leecher, 0, 0, 0, 1800, 1800));
+
+        // Partial seeds don't download either
+        let partial_seed = parse("8.8.8.8", "info_hash=AAAAAAAAAAAAAAAAAAAA&port=3333&left=10&event=paused");
+        assert_eq!(announce_reply(2, 1, 1800, 1800, &cached.leechers, &cached.leechers6), cached.reply(&partial_seed, 50, 0, 0, 1800, 1800));
+    }
+
+    #[test]
+    fn picks_peers() {
+        let seeders: Vec<u8> = (1..=4).flat_map(|i| [i; 6]).collect();
+        let leechers: Vec<u8> = (11..=14).flat_map(|i| [i; 6]).collect();
+
+        assert_eq!([[1; 6], [2; 6], [11; 6], [12; 6]].concat(), pick_peers(&seeders, &leechers, 6, None, false, 4));
+        assert_eq!([[1; 6], [2; 6], [3; 6], [11; 6]].concat(), pick_peers(&seeders, &leechers[..6], 6, None, false, 4));
+        assert_eq!([[11; 6], [12; 6], [13; 6]].concat(), pick_peers(&seeders, &leechers, 6, None, true, 3));
+        assert_eq!([[1; 6], [3; 6], [11; 6], [12; 6]].concat(), pick_peers(&seeders, &leechers, 6, Some(&[2; 6]), false, 4));
+        assert_eq!(Vec::<u8>::new(), pick_peers(&seeders, &[], 6, None, true, 4));
+    }
+
+    #[test]
+    fn splits_numwant() {
+        assert_eq!((25, 25), split_numwant(50, 100, 100));
+        assert_eq!((26, 25), split_numwant(51, 100, 100));
+        assert_eq!((10, 40), split_numwant(50, 10, 100));
+        assert_eq!((40, 10), split_numwant(50, 100, 10));
+        assert_eq!((3, 4), split_numwant(50, 3, 4));
+        assert_eq!((0, 0), split_numwant(0, 100, 100));
+        assert_eq!((1, 0), split_numwant(1, 100, 100));
+    }
+}
@@ -0,0 +1,212 @@
+// Clients are told to wait `min interval` between announces. Announces of the same torrent
+// from the same `ip_port` and in the same role that come sooner get a failure, before they touch the swarm.
+// So a peer that changed roles (e.g. finished without `completed`) goes through, and so does any
+// announce with an event (started / stopped / completed / paused), which starts the interval over.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::byte_functions::types::RawVal;
+use crate::query;
+use crate::store::{Mutation, Role, StoreResult, SwarmStore};
+use crate::swarm;
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Off,
+    /// Each kiryuu keeps its own
+    Memory,
+    /// Kept in the store, shared by every kiryuu using it
+    Store,
+}
+
+// Same idea as the memory store's shards
+const SHARDS: usize = 64;
+
+/// (torrent, `ip_port`) => when its interval is over, and the role it announced as
+type Intervals = HashMap<(RawVal<40>, Vec<u8>), (i64, Role)>;
+
+/// When each (torrent, `ip_port`) may announce again, in process
+pub struct Throttle {
+    shards: Vec<Mutex<Intervals>>,
+    hasher: RandomState,
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        return Throttle {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        };
+    }
+
+    fn shard(&self, info_hash: &RawVal<40>, ip_port: &[u8]) -> &Mutex<Intervals> {
+        return &self.shards[self.hasher.hash_one((info_hash, ip_port)) as usize % SHARDS];
+    }
+
+    /// Start the peer's `interval_ms` for the torrent as `role`, unless one is still running for the same role:
+    /// then the ms left of it
+    pub fn throttle(&self, info_hash: &RawVal<40>, ip_port: &[u8], role: Role, time_now_ms: i64, interval_ms: i64) -> Option<i64> {
+        let mut shard = self.shard(info_hash, ip_port).lock().expect("poisoned shard");
+
+        if let Some(&(until, throttled_role)) = shard.get(&(*info_hash, ip_port.to_vec())) {
+            if until > time_now_ms && throttled_role == role {
+                return Some(until - time_now_ms);
+            }
+        }
+
+        shard.insert((*info_hash, ip_port.to_vec()), (time_now_ms + interval_ms, role));
+        return None;
+    }
+
+    /// Let the peer announce the torrent again right away
+    pub fn forget(&self, info_hash: &RawVal<40>, ip_port: &[u8]) {
+        self.shard(info_hash, ip_port).lock().expect("poisoned shard").remove(&(*info_hash, ip_port.to_vec()));
+    }
+
+    /// Drop the intervals that are over
+    pub fn prune(&self, time_now_ms: i64) {
+        for shard in &self.shards {
+            shard.lock().expect("poisoned shard").retain(|_, &mut (until, _)| until > time_now_ms);
+        }
+    }
+}
+
+pub struct RateLimiter {
+    mode: Mode,
+    throttle: Throttle,
+}
+
+impl RateLimiter {
+    pub fn new(mode: Mode) -> RateLimiter {
+        return RateLimiter { mode, throttle: Throttle::new() };
+    }
+
+    /// How many ms the announce is early by, None if it can go ahead
+    pub async fn check(&self, store: &dyn SwarmStore, parsed: &query::PeerInfo, time_now_ms: i64, interval_ms: i64) -> StoreResult<Option<i64>> {
+        let (primary, _) = swarm::endpoints(parsed);
+
+        return match (self.mode, &parsed.event, swarm::role_after(parsed)) {
+            (Mode::Off, _, _) => Ok(None),
+            (Mode::Memory, query::Event::Unknown, Some(role)) => Ok(self.throttle.throttle(&parsed.info_hash, primary.ip_port, role, time_now_ms, interval_ms)),
+            (Mode::Store, query::Event::Unknown, Some(role)) => store.throttle(&parsed.info_hash, primary.ip_port, role, time_now_ms, interval_ms).await,
+            // Events go through, and the next regular announce starts a new interval.
+            // Whenever the peer comes back after stopping (or restarting, with `started`), it's a new start.
+            (Mode::Memory, _, _) => {
+                self.throttle.forget(&parsed.info_hash, primary.ip_port);
+                Ok(None)
+            },
+            (Mode::Store, _, _) => {
+                store.apply(vec![Mutation::Unthrottle { info_hash: parsed.info_hash, ip_port: primary.ip_port.to_vec() }]).await?;
+                Ok(None)
+            },
+        };
+    }
+
+    /// The announce didn't make it into the swarm after all, so don't hold the peer's retry back
+    pub fn forget(&self, parsed: &query::PeerInfo) {
+        if self.mode == Mode::Memory {
+            self.throttle.forget(&parsed.info_hash, swarm::endpoints(parsed).0.ip_port);
+        }
+    }
+
+    pub fn prune(&self, time_now_ms: i64) {
+        if self.mode == Mode::Memory {
+            self.throttle.prune(time_now_ms);
+        }
+    }
+}
+
+/// `retry in` for an announce `early_ms` too early, rounded up to a whole minute
+pub fn retry_in(early_ms: i64) -> query::RetryIn {
+    return query::RetryIn::Minutes(((early_ms + 59_999) / 60_000).max(1) as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const INFO_HASH: RawVal<40> = RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+
+    fn announce(ip_port: &[u8; 6], event: query::Event) -> query::PeerInfo {
+        return query::PeerInfo { ip_port: Some(*ip_port), ip6_port: None, info_hash: INFO_HASH, is_seeding: false, event, numwant: None, uploaded: 0, downloaded: 0 };
+    }
+
+    #[test]
+    fn throttles_until_the_interval_is_over() {
+        let throttle = Throttle::new();
+
+        assert_eq!(None, throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Leecher, 1000, 500));
+        assert_eq!(Some(300), throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Leecher, 1200, 500));
+        // Other peers & torrents have their own
+        assert_eq!(None, throttle.throttle(&INFO_HASH, b"BBBBBB", Role::Leecher, 1200, 500));
+        assert_eq!(None, throttle.throttle(&RawVal(*b"42AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), b"AAAAAA", Role::Leecher, 1200, 500));
+
+        assert_eq!(None, throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Leecher, 1500, 500));
+        assert_eq!(Some(100), throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Leecher, 1900, 500));
+
+        // Changing roles starts a new interval
+        assert_eq!(None, throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Seeder, 1900, 500));
+        assert_eq!(Some(500), throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Seeder, 1900, 500));
+
+        throttle.forget(&INFO_HASH, b"AAAAAA");
+        assert_eq!(None, throttle.throttle(&INFO_HASH, b"AAAAAA", Role::Seeder, 1900, 500));
+
+        throttle.prune(2399);
+        assert_eq!(1, throttle.shards.iter().map(|shard| shard.lock().unwrap().len()).sum::<usize>());
+    }
+
+    #[actix_web::test]
+    async fn lets_events_through() {
+        for mode in [Mode::Memory, Mode::Store] {
+            let store = MemoryStore::new();
+            let limiter = RateLimiter::new(mode);
+
+            assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1000, 500).await.unwrap());
+            assert_eq!(Some(400), limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1100, 500).await.unwrap());
+            assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", query::Event::Completed), 1100, 500).await.unwrap());
+            assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1200, 500).await.unwrap());
+
+            // Every event resets it
+            for event in [query::Event::Stopped, query::Event::Started, query::Event::Paused] {
+                assert_eq!(Some(500), limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1200, 500).await.unwrap());
+                assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", event), 1200, 500).await.unwrap());
+                assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1200, 500).await.unwrap());
+            }
+        }
+
+        let limiter = RateLimiter::new(Mode::Off);
+        for time_ms in [1000, 1001] {
+            assert_eq!(None, limiter.check(&MemoryStore::new(), &announce(b"AAAAAA", query::Event::Unknown), time_ms, 500).await.unwrap());
+        }
+    }
+
+    #[actix_web::test]
+    async fn lets_role_changes_through() {
+        for mode in [Mode::Memory, Mode::Store] {
+            let store = MemoryStore::new();
+            let limiter = RateLimiter::new(mode);
+            let seeding = query::PeerInfo { is_seeding: true, ..announce(b"AAAAAA", query::Event::Unknown) };
+
+            assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1000, 500).await.unwrap());
+            // Finished, without `completed`
+            assert_eq!(None, limiter.check(&store, &seeding, 1100, 500).await.unwrap());
+            assert_eq!(Some(400), limiter.check(&store, &seeding, 1200, 500).await.unwrap());
+            // And leeching again, e.g. new files in the torrent
+            assert_eq!(None, limiter.check(&store, &announce(b"AAAAAA", query::Event::Unknown), 1300, 500).await.unwrap());
+        }
+    }
+
+    #[test]
+    fn rounds_retry_up() {
+        assert_eq!(query::RetryIn::Minutes(1), retry_in(1));
+        assert_eq!(query::RetryIn::Minutes(1), retry_in(60_000));
+        assert_eq!(query::RetryIn::Minutes(2), retry_in(60_001));
+    }
+}
@@ -0,0 +1,38 @@
+// Peers that never send `stopped` would otherwise stay in the store forever,
+// only hidden from replies by the `--peer-window`.
+// Same for torrents nobody announces anymore. So every so often we prune both.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::web;
+
+use crate::AppState;
+
+pub struct Config {
+    pub interval: Duration,
+    /// How many torrents to prune per step
+    pub batch_size: usize,
+    /// Torrents without an announce for this long are dropped, stats and all
+    pub torrent_ttl: Duration,
+}
+
+/// Reap forever, every `config.interval`
+pub async fn run(data: web::Data<AppState>, config: Config) {
+    let mut interval = actix_web::rt::time::interval(config.interval);
+    let torrent_ttl_ms = i64::try_from(config.torrent_ttl.as_millis()).expect("fucc");
+
+    loop {
+        interval.tick().await;
+
+        let time_now = SystemTime::now().duration_since(UNIX_EPOCH).expect("fucked up");
+        let time_now_ms: i64 = i64::try_from(time_now.as_millis()).expect("fucc");
+
+        data.rate_limiter.prune(time_now_ms);
+
+        match data.store.reap(time_now_ms - data.settings.get().peer_window_ms, time_now_ms - torrent_ttl_ms, config.batch_size).await {
+            Ok(stats) if stats.peers > 0 || stats.torrents > 0 => println!("Reaped {} peers, {} torrents", stats.peers, stats.torrents),
+            Ok(_) => (),
+            Err(e) => println!("Err during reap {}", e),
+        }
+    }
+}
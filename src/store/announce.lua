@@ -0,0 +1,103 @@
+-- Records one announce atomically, see `RedisStore::announce`.
+-- The same role transitions as `swarm::plan_announce`, the tests in `redis_store` run this against it.
+-- Besides TORRENTS, only touches the torrent's own keys, which share a hash tag. On Redis Cluster
+-- TORRENTS is in another slot, so it's left out and the torrent indexed with the other mutations.
+-- KEYS: stats hash, cache key (for this numwant),
+--       seeders, leechers, partial seeds (primary family),
+--       seeders, leechers, partial seeds (other family), IPv6 mirrors,
+--       TORRENTS (unless left out), every cache key...
+-- ARGV: time now (ms), event ('stopped', 'completed', 'paused' or ''), is seeding ('1' or '0'),
+--       primary ip_port, secondary ip_port ('' if none), infohash ('' if TORRENTS is left out)
+-- Returns {seed count mod, leech count mod, partial seed count mod,
+--          cached reply from before the announce ('' if none)}
+
+local time_now, event, is_seeding = ARGV[1], ARGV[2], ARGV[3] == '1'
+local ip_port, secondary, info_hash = ARGV[4], ARGV[5], ARGV[6]
+
+-- Each role, as in `swarm::ROLES`: its stats hash counter, and its set in the primary & other family
+local roles = {
+    {counter = 'seeders', set = KEYS[3], other_set = KEYS[6]},
+    {counter = 'leechers', set = KEYS[4], other_set = KEYS[7]},
+    {counter = 'partial_seeds', set = KEYS[5], other_set = KEYS[8]},
+}
+local mirrors = KEYS[9]
+local torrents = info_hash ~= '' and KEYS[10] or nil
+local cache_keys_from = torrents and 11 or 10
+
+-- The role the peer has after this announce (`swarm::role_after`), nil if it stopped
+local after = nil
+if event == 'stopped' then
+    after = nil
+elseif is_seeding then
+    after = roles[1]
+elseif event == 'paused' then
+    after = roles[3]
+else
+    after = roles[2]
+end
+
+local cached = redis.call('GET', KEYS[2]) or ''
+
+local is_new = redis.call('HSET', KEYS[1], 'last_active', time_now) -- To "update" the torrent
+
+-- Index it for the reaper. A torrent we hadn't seen (or had reaped) is added as of now,
+-- so a reaper that's about to forget it leaves it be
+if torrents and is_new == 1 then
+    redis.call('ZADD', torrents, time_now, info_hash)
+elseif torrents then
+    redis.call('ZADD', torrents, 'NX', time_now, info_hash)
+end
+
+-- Dual stack peer, mirror it into the other family's sets without counting it
+if secondary ~= '' then
+    for _, role in ipairs(roles) do
+        if role == after then
+            redis.call('ZADD', role.other_set, time_now, secondary)
+        else
+            redis.call('ZREM', role.other_set, secondary)
+        end
+    end
+
+    if after then
+        redis.call('ZADD', mirrors, time_now, secondary)
+    else
+        redis.call('ZREM', mirrors, secondary)
+    end
+end
+
+-- An IPv6 peer that used to be a dual stack one's mirror is counted from now on
+local is_mirror = redis.call('ZREM', mirrors, ip_port) == 1
+
+local count_mods = {}
+local changed = false
+
+for i, role in ipairs(roles) do
+    local is_in = redis.call('ZSCORE', role.set, ip_port) ~= false
+    local counted = is_in and not is_mirror
+
+    -- Upsert it regardless to update timestamp for the guy
+    if role == after then
+        redis.call('ZADD', role.set, time_now, ip_port)
+    elseif is_in then
+        redis.call('ZREM', role.set, ip_port)
+    end
+
+    count_mods[i] = (role == after and 1 or 0) - (counted and 1 or 0)
+    if count_mods[i] ~= 0 then
+        redis.call('HINCRBY', KEYS[1], role.counter, count_mods[i])
+        changed = true
+    end
+end
+
+-- They just completed, increment the downloaded count for the infohash stats
+if event == 'completed' and is_seeding then
+    redis.call('HINCRBY', KEYS[1], 'downloaded', 1)
+end
+
+if changed then
+    for i = cache_keys_from, #KEYS do
+        redis.call('DEL', KEYS[i])
+    end
+end
+
+return {count_mods[1], count_mods[2], count_mods[3], cached}
@@ -0,0 +1,362 @@
+// Redis Cluster support. A torrent's keys all share the `{<infohash>}` hash tag
+// (see `byte_functions`), so its scripts & pipelines only ever touch one slot.
+// This routes each command to the master owning its slot, splitting up pipelines
+// that span several (e.g. scrapes), and follows the cluster's redirects when slots move.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+
+const SLOTS: u16 = 16384;
+
+// A slot can get redirected more than once while it's being migrated
+const MAX_REDIRECTS: usize = 5;
+
+/// CRC16 (XMODEM), which is what Redis Cluster hashes keys with
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    return crc;
+}
+
+/// The slot of `key`. Only the part in the first (non empty) `{...}` is hashed, if there is one.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|open| {
+        key[open + 1..].iter().position(|&b| b == b'}').filter(|&len| len > 0).map(|len| &key[open + 1..open + 1 + len])
+    });
+
+    return crc16(tag.unwrap_or(key)) % SLOTS;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Route {
+    Slot(u16),
+    AllMasters,
+    Any,
+}
+
+fn route(cmd: &Cmd) -> Route {
+    let args: Vec<&[u8]> = cmd.args_iter().filter_map(|arg| match arg {
+        Arg::Simple(arg) => Some(arg),
+        Arg::Cursor => None,
+    }).collect();
+
+    let name = match args.first() {
+        Some(name) => name.to_ascii_uppercase(),
+        None => return Route::Any,
+    };
+
+    return match &name[..] {
+        // So every master has our scripts
+        b"SCRIPT" => Route::AllMasters,
+        b"EVALSHA" | b"EVAL" => match args.get(2).map(|numkeys| *numkeys != b"0") {
+            Some(true) => args.get(3).map_or(Route::Any, |key| Route::Slot(key_slot(key))),
+            _ => Route::Any,
+        },
+        b"PING" => Route::Any,
+        _ => args.get(1).map_or(Route::Any, |key| Route::Slot(key_slot(key))),
+    };
+}
+
+fn parse_slots(reply: &Value, asked: &str) -> RedisResult<Vec<(u16, u16, String)>> {
+    let invalid = || RedisError::from((ErrorKind::TypeError, "Unexpected CLUSTER SLOTS reply"));
+
+    let entries = match reply {
+        Value::Bulk(entries) => entries,
+        _ => return Err(invalid()),
+    };
+
+    return entries.iter().map(|entry| {
+        let items = match entry {
+            Value::Bulk(items) if items.len() >= 3 => items,
+            _ => return Err(invalid()),
+        };
+        let master = match &items[2] {
+            Value::Bulk(master) if master.len() >= 2 => master,
+            _ => return Err(invalid()),
+        };
+
+        let ip: String = redis::from_redis_value(&master[0])?;
+        let port: u16 = redis::from_redis_value(&master[1])?;
+
+        // An empty IP means the node we asked
+        let host = match ip.as_str() {
+            "" => asked.rsplit_once(':').map_or(asked, |(host, _)| host).to_string(),
+            _ => ip,
+        };
+
+        return Ok((redis::from_redis_value(&items[0])?, redis::from_redis_value(&items[1])?, format!("{}:{}", host, port)));
+    }).collect();
+}
+
+#[derive(Default)]
+struct Topology {
+    /// (first slot, last slot, master's host:port)
+    slots: Vec<(u16, u16, String)>,
+    nodes: HashMap<String, ConnectionManager>,
+}
+
+impl Topology {
+    fn master(&self, slot: u16) -> Option<&str> {
+        return self.slots.iter().find(|(first, last, _)| (*first..=*last).contains(&slot)).map(|(_, _, addr)| addr.as_str());
+    }
+
+    fn masters(&self) -> Vec<String> {
+        let mut masters: Vec<String> = self.slots.iter().map(|(_, _, addr)| addr.clone()).collect();
+        masters.sort();
+        masters.dedup();
+        return masters;
+    }
+}
+
+/// What we're sending to a node
+#[derive(Clone, Copy)]
+enum Request<'a> {
+    Single(&'a Cmd),
+    Pipeline(&'a Pipeline, usize, usize),
+}
+
+impl<'a> Request<'a> {
+    /// Send it to `node`. `asking` if the slot is being migrated there (ASK redirect).
+    async fn send(self, mut node: ConnectionManager, asking: bool) -> RedisResult<Vec<Value>> {
+        if !asking {
+            return match self {
+                Request::Single(cmd) => Ok(vec![node.req_packed_command(cmd).await?]),
+                Request::Pipeline(pipeline, offset, count) => node.req_packed_commands(pipeline, offset, count).await,
+            };
+        }
+
+        // Sent together, so nothing else on the (multiplexed) connection gets in between
+        let mut with_asking = redis::pipe();
+        with_asking.cmd("ASKING");
+
+        return match self {
+            Request::Single(cmd) => {
+                with_asking.add_command(cmd.clone());
+                let mut reply = node.req_packed_commands(&with_asking, 1, 1).await?;
+                Ok(vec![reply.remove(0)])
+            },
+            Request::Pipeline(pipeline, offset, count) => {
+                for cmd in pipeline.cmd_iter() {
+                    with_asking.add_command(cmd.clone());
+                }
+                node.req_packed_commands(&with_asking, offset + 1, count).await
+            },
+        };
+    }
+}
+
+/// Connections to each master of a cluster, found through the `seeds`
+#[derive(Clone)]
+pub struct ClusterConnection {
+    seeds: Vec<String>,
+    topology: Arc<RwLock<Topology>>,
+}
+
+impl ClusterConnection {
+    /// `seeds` are the host:port of some of the cluster's nodes
+    pub async fn connect(seeds: Vec<String>) -> RedisResult<ClusterConnection> {
+        let connection = ClusterConnection { seeds, topology: Arc::new(RwLock::new(Topology::default())) };
+        connection.refresh_slots().await?;
+
+        return Ok(connection);
+    }
+
+    async fn node(&self, addr: &str) -> RedisResult<ConnectionManager> {
+        if let Some(node) = self.topology.read().expect("poisoned topology").nodes.get(addr) {
+            return Ok(node.clone());
+        }
+
+        let node = ConnectionManager::new(redis::Client::open(format!("redis://{}", addr))?).await?;
+        self.topology.write().expect("poisoned topology").nodes.entry(addr.to_string()).or_insert_with(|| node.clone());
+
+        return Ok(node);
+    }
+
+    /// Ask the masters we know of (or the seeds) who owns which slots now
+    async fn refresh_slots(&self) -> RedisResult<()> {
+        let mut candidates = self.topology.read().expect("poisoned topology").masters();
+        candidates.extend(self.seeds.iter().cloned());
+
+        let mut last_error = RedisError::from((ErrorKind::ClusterDown, "No cluster nodes to ask"));
+
+        for addr in candidates {
+            let reply = match self.node(&addr).await {
+                Ok(mut node) => redis::cmd("CLUSTER").arg("SLOTS").query_async(&mut node).await,
+                Err(e) => Err(e),
+            };
+
+            match reply.and_then(|reply| parse_slots(&reply, &addr)) {
+                Ok(slots) => {
+                    let mut topology = self.topology.write().expect("poisoned topology");
+                    topology.slots = slots;
+                    // Forget nodes that aren't masters anymore
+                    let masters = topology.masters();
+                    topology.nodes.retain(|addr, _| masters.contains(addr));
+                    return Ok(());
+                },
+                Err(e) => last_error = e,
+            }
+        }
+
+        return Err(last_error);
+    }
+
+    fn master(&self, route: Route) -> RedisResult<String> {
+        let topology = self.topology.read().expect("poisoned topology");
+        let master = match route {
+            Route::Slot(slot) => topology.master(slot),
+            Route::AllMasters | Route::Any => topology.slots.first().map(|(_, _, addr)| addr.as_str()),
+        };
+
+        return master.map(str::to_string).ok_or_else(|| RedisError::from((ErrorKind::ClusterDown, "Slot not served by any node")));
+    }
+
+    /// Send the request to the master of `route`, following redirects
+    async fn send(&self, route: Route, request: Request<'_>) -> RedisResult<Vec<Value>> {
+        let mut addr = self.master(route)?;
+        let mut asking = false;
+
+        for _ in 0..MAX_REDIRECTS {
+            let e = match request.send(self.node(&addr).await?, asking).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => e,
+            };
+
+            match (e.kind(), e.redirect_node()) {
+                (ErrorKind::Moved, Some((host, port))) => {
+                    // The slot moved for good, pick up the new layout while at it
+                    addr = format!("{}:{}", host, port);
+                    asking = false;
+                    self.refresh_slots().await?;
+                },
+                (ErrorKind::Ask, Some((host, port))) => {
+                    addr = format!("{}:{}", host, port);
+                    asking = true;
+                },
+                _ => return Err(e),
+            }
+        }
+
+        return Err(RedisError::from((ErrorKind::ClusterDown, "Too many redirects")));
+    }
+
+    async fn send_pipeline(&self, pipeline: &Pipeline, offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let routes: Vec<Route> = pipeline.cmd_iter().map(route).collect();
+        let first = routes.iter().copied().find(|route| *route != Route::Any).unwrap_or(Route::Any);
+
+        // All on one node (e.g. MULTI / EXEC, or a torrent's commands)
+        let mut by_master: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, route) in routes.iter().enumerate() {
+            let master = match route {
+                Route::Any => self.master(first)?,
+                _ => self.master(*route)?,
+            };
+            by_master.entry(master).or_default().push(i);
+        }
+
+        if by_master.len() == 1 {
+            return self.send(first, Request::Pipeline(pipeline, offset, count)).await;
+        }
+
+        // Spread over several nodes. Send each its part (at once), and put the replies back in order.
+        // A slot moving in the middle of this can make us resend a part, but that only happens while resharding.
+        let parts: Vec<(Route, Pipeline, Vec<usize>)> = by_master.into_values().map(|indexes| {
+            let mut part = redis::pipe();
+            for &i in &indexes {
+                part.add_command(pipeline.cmd_iter().nth(i).expect("index from the same pipeline").clone());
+            }
+            (routes[indexes[0]], part, indexes)
+        }).collect();
+
+        let replies = future::join_all(parts.iter().map(|(route, part, indexes)| {
+            self.send(*route, Request::Pipeline(part, 0, indexes.len()))
+        })).await;
+
+        let mut ordered: Vec<Option<Value>> = vec![None; routes.len()];
+        for ((_, _, indexes), reply) in parts.iter().zip(replies) {
+            for (&i, value) in indexes.iter().zip(reply?) {
+                ordered[i] = Some(value);
+            }
+        }
+
+        return Ok(ordered.into_iter().skip(offset).take(count).map(|value| value.unwrap_or(Value::Nil)).collect());
+    }
+}
+
+impl ConnectionLike for ClusterConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        return (async move {
+            let route = route(cmd);
+
+            if route == Route::AllMasters {
+                let masters = self.topology.read().expect("poisoned topology").masters();
+                let mut reply = Value::Okay;
+                for addr in masters {
+                    reply = Request::Single(cmd).send(self.node(&addr).await?, false).await?.remove(0);
+                }
+                return Ok(reply);
+            }
+
+            return Ok(self.send(route, Request::Single(cmd)).await?.remove(0));
+        }).boxed();
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, pipeline: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        let future: BoxFuture<'a, RedisResult<Vec<Value>>> = self.send_pipeline(pipeline, offset, count).boxed();
+        return future;
+    }
+
+    fn get_db(&self) -> i64 {
+        return 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_keys_like_redis() {
+        assert_eq!(0x31C3, crc16(b"123456789"));
+        assert_eq!(12182, key_slot(b"foo"));
+        assert_eq!(key_slot(b"foo"), key_slot(b"{foo}_seeders"));
+        assert_eq!(key_slot(b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}"), key_slot(b"{41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA}_cache_050"));
+
+        // Empty tags don't count
+        assert_eq!(crc16(b"{}foo") % SLOTS, key_slot(b"{}foo"));
+    }
+
+    #[test]
+    fn routes_by_key() {
+        assert_eq!(Route::Slot(12182), route(redis::cmd("ZADD").arg("foo").arg(1).arg("bar")));
+        assert_eq!(Route::Slot(12182), route(redis::cmd("EVALSHA").arg("abc").arg(2).arg("{foo}_seeders").arg("{foo}")));
+        assert_eq!(Route::Any, route(redis::cmd("EVALSHA").arg("abc").arg(0)));
+        assert_eq!(Route::AllMasters, route(redis::cmd("SCRIPT").arg("LOAD").arg("return 1")));
+        assert_eq!(Route::Any, route(&redis::cmd("PING")));
+    }
+
+    #[test]
+    fn parses_cluster_slots() {
+        let node = |ip: &str, port: i64| Value::Bulk(vec![Value::Data(ip.as_bytes().to_vec()), Value::Int(port), Value::Data(b"id".to_vec())]);
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![Value::Int(0), Value::Int(8191), node("10.0.0.1", 7000), node("10.0.0.2", 7001)]),
+            Value::Bulk(vec![Value::Int(8192), Value::Int(16383), node("", 7002)]),
+        ]);
+
+        assert_eq!(vec![
+            (0, 8191, "10.0.0.1:7000".to_string()),
+            (8192, 16383, "10.0.0.3:7002".to_string()),
+        ], parse_slots(&reply, "10.0.0.3:7002").unwrap());
+    }
+}
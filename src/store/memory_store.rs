@@ -0,0 +1,439 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+
+use crate::byte_functions::types::RawVal;
+use crate::peer_selection::PeerSelection;
+use crate::rate_limit::Throttle;
+use crate::{private, query, swarm};
+
+use super::{Announced, Family, FamilyPeers, Mutation, PeerRole, ReapStats, Role, StoreResult, SwarmStore, TorrentStats};
+
+// Torrents are spread over this many independently locked maps,
+// so announces for different torrents rarely wait on each other
+const SHARDS: usize = 64;
+
+#[derive(Default)]
+struct Torrent {
+    /// `ip_port` => last announce, one map per (family, role), see `slot`
+    peers: [HashMap<Vec<u8>, i64>; 6],
+    /// The IPv6 `ip_port`s that are dual stack peers' mirrors => last announce, see `PeerRole::is_mirror`
+    mirrors: HashMap<Vec<u8>, i64>,
+    seeders: i64,
+    leechers: i64,
+    partial_seeds: i64,
+    downloaded: i64,
+    last_active_ms: i64,
+}
+
+fn slot(family: Family, role: Role) -> usize {
+    return match (family, role) {
+        (Family::V4, Role::Seeder) => 0,
+        (Family::V4, Role::Leecher) => 1,
+        (Family::V4, Role::PartialSeed) => 2,
+        (Family::V6, Role::Seeder) => 3,
+        (Family::V6, Role::Leecher) => 4,
+        (Family::V6, Role::PartialSeed) => 5,
+    };
+}
+
+impl Torrent {
+    fn active(&self, family: Family, role: Role, max_limit: i64, time_now_ms: i64) -> Vec<(Vec<u8>, i64)> {
+        return self.peers[slot(family, role)].iter()
+        .filter(|(_, &last_seen)| last_seen >= max_limit && last_seen <= time_now_ms)
+        .map(|(peer, &last_seen)| (peer.clone(), last_seen))
+        .collect();
+    }
+
+    fn role(&self, family: Family, ip_port: &[u8]) -> PeerRole {
+        return PeerRole {
+            is_seeder: self.peers[slot(family, Role::Seeder)].contains_key(ip_port),
+            is_leecher: self.peers[slot(family, Role::Leecher)].contains_key(ip_port),
+            is_partial_seed: self.peers[slot(family, Role::PartialSeed)].contains_key(ip_port),
+            is_mirror: family == Family::V6 && self.mirrors.contains_key(ip_port),
+        };
+    }
+
+    /// Apply a mutation of this torrent
+    fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::TouchTorrent { time_ms, .. } => self.last_active_ms = time_ms,
+            Mutation::UpsertPeer { family, role, ip_port, time_ms, .. } => {
+                self.peers[slot(family, role)].insert(ip_port, time_ms);
+            },
+            Mutation::RemovePeer { family, role, ip_port, .. } => {
+                self.peers[slot(family, role)].remove(&ip_port);
+            },
+            Mutation::MarkMirror { ip_port, time_ms, .. } => {
+                self.mirrors.insert(ip_port, time_ms);
+            },
+            Mutation::UnmarkMirror { ip_port, .. } => {
+                self.mirrors.remove(&ip_port);
+            },
+            Mutation::UpdateCounters { seeders, leechers, partial_seeds, downloaded, .. } => {
+                self.seeders += seeders;
+                self.leechers += leechers;
+                self.partial_seeds += partial_seeds;
+                self.downloaded += downloaded;
+            },
+            // Nothing cached
+            Mutation::InvalidateCache { .. } | Mutation::CacheReply { .. } => (),
+            // The global stats are only kept in redis
+            Mutation::IncrementStat { .. } => (),
+            // Kept outside the torrents, see `MemoryStore::apply`
+            Mutation::Unthrottle { .. } => (),
+            // Every torrent is in the shards already
+            Mutation::IndexTorrent { .. } => (),
+        }
+    }
+
+    fn peer_count(&self) -> i64 {
+        return self.peers.iter().map(|peers| peers.len() as i64).sum();
+    }
+
+    /// The peers in `role`, counted the way announces count them: by their primary endpoint,
+    /// i.e. leaving out dual stack peers' IPv6 mirrors. Same as the redis store recounts.
+    fn role_count(&self, role: Role) -> i64 {
+        let v6 = self.peers[slot(Family::V6, role)].keys().filter(|ip_port| !self.mirrors.contains_key(*ip_port)).count();
+        return (self.peers[slot(Family::V4, role)].len() + v6) as i64;
+    }
+}
+
+/// Keeps every swarm in process. Doesn't cache replies, building them is cheap without the round trips.
+pub struct MemoryStore {
+    shards: Vec<RwLock<HashMap<RawVal<40>, Torrent>>>,
+    hasher: RandomState,
+    throttle: Throttle,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        return MemoryStore {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            throttle: Throttle::new(),
+        };
+    }
+
+    fn shard(&self, info_hash: &RawVal<40>) -> &RwLock<HashMap<RawVal<40>, Torrent>> {
+        return &self.shards[self.hasher.hash_one(info_hash) as usize % SHARDS];
+    }
+
+}
+
+#[async_trait(?Send)]
+impl SwarmStore for MemoryStore {
+    /// Atomic since the torrent's shard stays locked throughout
+    async fn announce(&self, parsed: &query::PeerInfo, _numwant: u16, time_now_ms: i64) -> StoreResult<Announced> {
+        let mut shard = self.shard(&parsed.info_hash).write().expect("poisoned shard");
+        let torrent = shard.entry(parsed.info_hash).or_default();
+
+        let (primary, _) = swarm::endpoints(parsed);
+        let role = torrent.role(primary.family, primary.ip_port);
+
+        // Nothing's ever cached here
+        let plan = swarm::plan_announce(parsed, &role, swarm::CacheState::Miss, time_now_ms);
+
+        for mutation in plan.mutations {
+            torrent.apply(mutation);
+        }
+
+        return Ok(Announced {
+            seed_count_mod: plan.seed_count_mod,
+            leech_count_mod: plan.leech_count_mod,
+            partial_count_mod: plan.partial_count_mod,
+            cached_reply: None,
+        });
+    }
+
+    async fn fetch_peers(
+        &self,
+        info_hash: &RawVal<40>,
+        families: &[Family],
+        numwant: u16,
+        selection: &dyn PeerSelection,
+        max_limit: i64,
+        time_now_ms: i64,
+    ) -> StoreResult<Vec<FamilyPeers>> {
+        let shard = self.shard(info_hash).read().expect("poisoned shard");
+        let torrent = shard.get(info_hash);
+
+        return Ok(families.iter().map(|&family| {
+            let active = |role: Role| match torrent {
+                Some(torrent) => torrent.active(family, role, max_limit, time_now_ms),
+                None => vec![],
+            };
+
+            FamilyPeers {
+                seeders: selection.pick(active(Role::Seeder), numwant),
+                leechers: selection.pick(active(Role::Leecher), numwant),
+                partial_seeds: selection.pick(active(Role::PartialSeed), numwant),
+            }
+        }).collect());
+    }
+
+    async fn fetch_stats(&self, info_hashes: &[RawVal<40>]) -> StoreResult<Vec<TorrentStats>> {
+        let stats = info_hashes.iter().map(|info_hash| {
+            let shard = self.shard(info_hash).read().expect("poisoned shard");
+
+            match shard.get(info_hash) {
+                Some(torrent) => TorrentStats {
+                    seeders: torrent.seeders.max(0),
+                    leechers: torrent.leechers.max(0),
+                    partial_seeds: torrent.partial_seeds.max(0),
+                    downloaded: torrent.downloaded.max(0),
+                },
+                None => TorrentStats { seeders: 0, leechers: 0, partial_seeds: 0, downloaded: 0 },
+            }
+        }).collect();
+
+        return Ok(stats);
+    }
+
+    async fn apply(&self, mutations: Vec<Mutation>) -> StoreResult<()> {
+        for mutation in mutations {
+            let info_hash = match mutation {
+                Mutation::TouchTorrent { info_hash, .. }
+                | Mutation::UpsertPeer { info_hash, .. }
+                | Mutation::RemovePeer { info_hash, .. }
+                | Mutation::MarkMirror { info_hash, .. }
+                | Mutation::UnmarkMirror { info_hash, .. }
+                | Mutation::UpdateCounters { info_hash, .. } => info_hash,
+                Mutation::Unthrottle { info_hash, ip_port } => {
+                    self.throttle.forget(&info_hash, &ip_port);
+                    continue;
+                },
+                // Nothing to do for these, see `Torrent::apply`
+                Mutation::InvalidateCache { .. } | Mutation::CacheReply { .. } | Mutation::IncrementStat { .. } | Mutation::IndexTorrent { .. } => continue,
+            };
+
+            self.shard(&info_hash).write().expect("poisoned shard").entry(info_hash).or_default().apply(mutation);
+        }
+
+        return Ok(());
+    }
+
+    async fn ping(&self) -> StoreResult<()> {
+        Ok(())
+    }
+
+    /// No users in process, private mode needs redis
+    async fn fetch_user(&self, _key: &str, _passkey: &str) -> StoreResult<Option<String>> {
+        Ok(None)
+    }
+
+    async fn account(&self, _report: private::Report, _report_ttl_ms: i64) -> StoreResult<()> {
+        Ok(())
+    }
+
+    /// No sets in process, they're only read from redis
+    async fn fetch_set(&self, _key: &str) -> StoreResult<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+
+    async fn throttle(&self, info_hash: &RawVal<40>, ip_port: &[u8], role: Role, time_now_ms: i64, interval_ms: i64) -> StoreResult<Option<i64>> {
+        Ok(self.throttle.throttle(info_hash, ip_port, role, time_now_ms, interval_ms))
+    }
+
+    /// A shard at a time, `batch_size` doesn't matter here
+    async fn reap(&self, peer_expiry: i64, torrent_expiry: i64, _batch_size: usize) -> StoreResult<ReapStats> {
+        let mut stats = ReapStats::default();
+        // Announce intervals end well inside the peer window
+        self.throttle.prune(peer_expiry);
+
+        for shard in &self.shards {
+            shard.write().expect("poisoned shard").retain(|_, torrent| {
+                if torrent.last_active_ms < torrent_expiry {
+                    stats.peers += torrent.peer_count();
+                    stats.torrents += 1;
+                    return false;
+                }
+
+                let before = torrent.peer_count();
+                for peers in torrent.peers.iter_mut() {
+                    peers.retain(|_, &mut last_seen| last_seen >= peer_expiry);
+                }
+                // Announced along with the mirrored peer, so they go stale together
+                torrent.mirrors.retain(|_, &mut last_seen| last_seen >= peer_expiry);
+
+                let removed = before - torrent.peer_count();
+                if removed > 0 {
+                    // Recount the same way as the redis store does
+                    torrent.seeders = torrent.role_count(Role::Seeder);
+                    torrent.leechers = torrent.role_count(Role::Leecher);
+                    torrent.partial_seeds = torrent.role_count(Role::PartialSeed);
+                    stats.peers += removed;
+                }
+
+                return true;
+            });
+        }
+
+        return Ok(stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_selection;
+
+    const INFO_HASH: RawVal<40> = RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+
+    fn upsert(family: Family, role: Role, ip_port: &[u8], time_ms: i64) -> Mutation {
+        return Mutation::UpsertPeer { info_hash: INFO_HASH, family, role, ip_port: ip_port.to_vec(), time_ms };
+    }
+
+    fn role(store: &MemoryStore, info_hash: &RawVal<40>, family: Family, ip_port: &[u8]) -> PeerRole {
+        return match store.shard(info_hash).read().unwrap().get(info_hash) {
+            Some(torrent) => torrent.role(family, ip_port),
+            None => PeerRole { is_seeder: false, is_leecher: false, is_partial_seed: false, is_mirror: false },
+        };
+    }
+
+    fn announce(is_seeding: bool, event: query::Event) -> query::PeerInfo {
+        return query::PeerInfo { ip_port: Some(*b"AAAAAA"), ip6_port: None, info_hash: INFO_HASH, is_seeding, event, numwant: None, uploaded: 0, downloaded: 0 };
+    }
+
+    #[actix_web::test]
+    async fn announces() {
+        let store = MemoryStore::new();
+
+        let announced = store.announce(&announce(false, query::Event::Unknown), 50, 100).await.unwrap();
+        assert_eq!((0, 1), (announced.seed_count_mod, announced.leech_count_mod));
+        assert!(announced.cached_reply.is_none());
+        assert!(role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_leecher);
+
+        // Again, already counted
+        let announced = store.announce(&announce(false, query::Event::Unknown), 50, 100).await.unwrap();
+        assert_eq!((0, 0), (announced.seed_count_mod, announced.leech_count_mod));
+
+        let announced = store.announce(&announce(true, query::Event::Completed), 50, 100).await.unwrap();
+        assert_eq!((1, -1), (announced.seed_count_mod, announced.leech_count_mod));
+
+        let stats = store.fetch_stats(&[INFO_HASH]).await.unwrap();
+        assert_eq!((1, 0, 1), (stats[0].seeders, stats[0].leechers, stats[0].downloaded));
+
+        // Other family, other peer, other torrent
+        assert!(role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_seeder);
+        assert!(!role(&store, &INFO_HASH, Family::V6, b"AAAAAA").is_seeder);
+        assert!(!role(&store, &INFO_HASH, Family::V4, b"BBBBBB").is_seeder);
+        assert!(!role(&store, &RawVal([b'B'; 40]), Family::V4, b"AAAAAA").is_seeder);
+
+        // Leeching again (no event), moves back without being counted twice
+        let announced = store.announce(&announce(false, query::Event::Unknown), 50, 100).await.unwrap();
+        assert_eq!((-1, 1), (announced.seed_count_mod, announced.leech_count_mod));
+        assert!(!role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_seeder);
+
+        // And seeding again, without `completed`
+        let announced = store.announce(&announce(true, query::Event::Unknown), 50, 100).await.unwrap();
+        assert_eq!((1, -1), (announced.seed_count_mod, announced.leech_count_mod));
+        assert!(!role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_leecher);
+
+        let stats = store.fetch_stats(&[INFO_HASH]).await.unwrap();
+        assert_eq!((1, 0, 1), (stats[0].seeders, stats[0].leechers, stats[0].downloaded));
+
+        let announced = store.announce(&announce(true, query::Event::Stopped), 50, 100).await.unwrap();
+        assert_eq!((-1, 0), (announced.seed_count_mod, announced.leech_count_mod));
+        assert!(!role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_seeder);
+
+        // A partial seed (BEP 21) is counted on its own, until it leeches again
+        let announced = store.announce(&announce(false, query::Event::Paused), 50, 100).await.unwrap();
+        assert_eq!((0, 0, 1), (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod));
+        assert!(role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_partial_seed);
+
+        let stats = store.fetch_stats(&[INFO_HASH]).await.unwrap();
+        assert_eq!((0, 0, 1), (stats[0].seeders, stats[0].leechers, stats[0].partial_seeds));
+
+        let announced = store.announce(&announce(false, query::Event::Started), 50, 100).await.unwrap();
+        assert_eq!((0, 1, -1), (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod));
+    }
+
+    #[actix_web::test]
+    async fn fetches_active_peers() {
+        let store = MemoryStore::new();
+        store.apply(vec![
+            upsert(Family::V4, Role::Seeder, b"AAAAAA", 100),
+            upsert(Family::V4, Role::Seeder, b"BBBBBB", 5), // Stale
+            upsert(Family::V4, Role::Leecher, b"CCCCCC", 200),
+            upsert(Family::V6, Role::Leecher, b"DDDDDDDDDDDDDDDDDD", 300),
+            upsert(Family::V4, Role::PartialSeed, b"EEEEEE", 300),
+        ]).await.unwrap();
+
+        let selection = peer_selection::new(peer_selection::Strategy::Oldest);
+        let peers = store.fetch_peers(&INFO_HASH, &[Family::V4, Family::V6], 50, selection.as_ref(), 10, 1000).await.unwrap();
+
+        assert_eq!(vec![b"AAAAAA".to_vec()], peers[0].seeders);
+        assert_eq!(vec![b"CCCCCC".to_vec()], peers[0].leechers);
+        assert_eq!(vec![b"EEEEEE".to_vec()], peers[0].partial_seeds);
+        assert!(peers[1].seeders.is_empty() && peers[1].partial_seeds.is_empty());
+        assert_eq!(vec![b"DDDDDDDDDDDDDDDDDD".to_vec()], peers[1].leechers);
+    }
+
+    #[actix_web::test]
+    async fn keeps_counters() {
+        let store = MemoryStore::new();
+        store.apply(vec![
+            Mutation::UpdateCounters { info_hash: INFO_HASH, seeders: 2, leechers: 1, partial_seeds: 2, downloaded: 1 },
+            Mutation::UpdateCounters { info_hash: INFO_HASH, seeders: -1, leechers: -3, partial_seeds: -1, downloaded: 0 },
+        ]).await.unwrap();
+
+        let stats = store.fetch_stats(&[INFO_HASH, RawVal([b'B'; 40])]).await.unwrap();
+        assert_eq!((1, 0, 1, 1), (stats[0].seeders, stats[0].leechers, stats[0].partial_seeds, stats[0].downloaded));
+        assert_eq!((0, 0, 0, 0), (stats[1].seeders, stats[1].leechers, stats[1].partial_seeds, stats[1].downloaded));
+    }
+
+    #[actix_web::test]
+    async fn reaps_stale_peers_and_torrents() {
+        let store = MemoryStore::new();
+        let stale_torrent = RawVal([b'B'; 40]);
+        store.apply(vec![
+            Mutation::TouchTorrent { info_hash: INFO_HASH, time_ms: 300 },
+            upsert(Family::V4, Role::Seeder, b"AAAAAA", 100), // Stale
+            upsert(Family::V4, Role::Seeder, b"BBBBBB", 300),
+            upsert(Family::V6, Role::Leecher, b"CCCCCCCCCCCCCCCCCC", 100), // Stale
+            upsert(Family::V4, Role::PartialSeed, b"EEEEEE", 100), // Stale
+            upsert(Family::V6, Role::PartialSeed, b"FFFFFFFFFFFFFFFFFF", 300),
+            Mutation::UpdateCounters { info_hash: INFO_HASH, seeders: 2, leechers: 1, partial_seeds: 2, downloaded: 4 },
+            Mutation::TouchTorrent { info_hash: stale_torrent, time_ms: 50 },
+            Mutation::UpsertPeer { info_hash: stale_torrent, family: Family::V4, role: Role::Leecher, ip_port: b"DDDDDD".to_vec(), time_ms: 50 },
+            Mutation::UpdateCounters { info_hash: stale_torrent, seeders: 0, leechers: 1, partial_seeds: 0, downloaded: 1 },
+        ]).await.unwrap();
+
+        assert_eq!(ReapStats { peers: 4, torrents: 1 }, store.reap(200, 60, 10).await.unwrap());
+
+        let stats = store.fetch_stats(&[INFO_HASH, stale_torrent]).await.unwrap();
+        assert_eq!((1, 0, 1, 4), (stats[0].seeders, stats[0].leechers, stats[0].partial_seeds, stats[0].downloaded));
+        assert_eq!((0, 0, 0), (stats[1].seeders, stats[1].leechers, stats[1].downloaded));
+        assert!(!role(&store, &INFO_HASH, Family::V4, b"AAAAAA").is_seeder);
+        assert!(role(&store, &INFO_HASH, Family::V4, b"BBBBBB").is_seeder);
+
+        // Nothing left to do
+        assert_eq!(ReapStats::default(), store.reap(200, 60, 10).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn recounts_dual_stack_peers_once() {
+        let store = MemoryStore::new();
+        let dual_stack = query::PeerInfo { ip6_port: Some(*b"DDDDDDDDDDDDDDDDDD"), ..announce(false, query::Event::Unknown) };
+        let other = query::PeerInfo { ip_port: Some(*b"BBBBBB"), ..announce(false, query::Event::Unknown) };
+
+        store.announce(&dual_stack, 50, 300).await.unwrap();
+        store.announce(&other, 50, 100).await.unwrap();
+        assert!(role(&store, &INFO_HASH, Family::V6, b"DDDDDDDDDDDDDDDDDD").is_mirror);
+
+        // Reaping the other peer recounts the dual stack one, but only once
+        assert_eq!(ReapStats { peers: 1, torrents: 0 }, store.reap(200, 60, 10).await.unwrap());
+        assert_eq!(1, store.fetch_stats(&[INFO_HASH]).await.unwrap()[0].leechers);
+
+        let announced = store.announce(&query::PeerInfo { event: query::Event::Stopped, ..dual_stack }, 50, 400).await.unwrap();
+        assert_eq!((0, -1, 0), (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod));
+
+        let stats = store.fetch_stats(&[INFO_HASH]).await.unwrap();
+        assert_eq!((0, 0, 0), (stats[0].seeders, stats[0].leechers, stats[0].partial_seeds));
+        assert!(!role(&store, &INFO_HASH, Family::V6, b"DDDDDDDDDDDDDDDDDD").is_mirror);
+    }
+}
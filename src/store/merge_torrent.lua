@@ -0,0 +1,16 @@
+-- Merges a torrent moved over from another shard, see `RedisStore::rebalance`.
+-- Its peers (and IPv6 mirrors) were already added (keeping the later announce), this fixes up its stats.
+-- KEYS: seeders, leechers, partial seeds, seeders6, leechers6, partial seeds6, stats hash, IPv6 mirrors, cache keys...
+-- ARGV: downloaded, last active (ms) on the old shard
+
+local last_active = tonumber(redis.call('HGET', KEYS[7], 'last_active'))
+if last_active == nil or last_active < tonumber(ARGV[2]) then
+    redis.call('HSET', KEYS[7], 'last_active', ARGV[2])
+end
+
+redis.call('HINCRBY', KEYS[7], 'downloaded', ARGV[1])
+recount({KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6]}, KEYS[8], KEYS[7])
+
+for i = 9, #KEYS do
+    redis.call('DEL', KEYS[i])
+end
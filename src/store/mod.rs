@@ -0,0 +1,129 @@
+// Where the swarms live. The announce / scrape logic (see `swarm`) only talks to
+// the `SwarmStore` trait. Redis is the main implementation, the in-memory one is
+// for small trackers (and tests) that don't want to run redis.
+
+mod cluster;
+mod memory_store;
+mod redis_store;
+mod sharding;
+
+pub use cluster::ClusterConnection;
+pub use memory_store::MemoryStore;
+pub use redis_store::{RedisConnection, RedisStore};
+// Defined with the announce logic, which doesn't depend on any store
+pub use crate::swarm::{Family, Mutation, PeerRole, Role};
+
+use async_trait::async_trait;
+
+use crate::byte_functions::types::RawVal;
+use crate::peer_selection::PeerSelection;
+use crate::{private, query};
+
+#[derive(clap::ValueEnum, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Redis,
+    /// Everything in process, gone on restart
+    Memory,
+}
+
+/// Compact peers (`ip_port`s), 6 bytes each for IPv4, 18 for IPv6
+pub type Peers = Vec<Vec<u8>>;
+
+/// What recording an announce did
+pub struct Announced {
+    pub seed_count_mod: i64,
+    pub leech_count_mod: i64,
+    pub partial_count_mod: i64,
+    /// The torrent's cached `query::CachedPeers` from before the announce, if the store caches them
+    pub cached_reply: Option<Vec<u8>>,
+}
+
+/// The peers of one address family we hand out
+pub struct FamilyPeers {
+    pub seeders: Peers,
+    pub leechers: Peers,
+    pub partial_seeds: Peers,
+}
+
+/// A torrent's counters from its stats hash
+pub struct TorrentStats {
+    pub seeders: i64,
+    pub leechers: i64,
+    pub partial_seeds: i64,
+    pub downloaded: i64,
+}
+
+/// What a `SwarmStore::reap` pass removed
+#[derive(Debug, Default, PartialEq)]
+pub struct ReapStats {
+    pub peers: i64,
+    pub torrents: i64,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Redis(redis::RedisError),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Redis(e) => write!(f, "redis: {}", e),
+        }
+    }
+}
+
+impl From<redis::RedisError> for StoreError {
+    fn from(e: redis::RedisError) -> Self {
+        return StoreError::Redis(e);
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+#[async_trait(?Send)]
+pub trait SwarmStore: Send + Sync {
+    /// Record the announce in the swarm (see `swarm::plan_announce`), atomically,
+    /// so overlapping announces from the same peer can't both count it.
+    /// Also gets the cached reply for `numwant`.
+    async fn announce(&self, parsed: &query::PeerInfo, numwant: u16, time_now_ms: i64) -> StoreResult<Announced>;
+
+    /// The torrent's active peers (last announce between `max_limit` and `time_now_ms`) of each family,
+    /// up to `numwant` each of seeders, leechers & partial seeds, picked by `selection`
+    async fn fetch_peers(
+        &self,
+        info_hash: &RawVal<40>,
+        families: &[Family],
+        numwant: u16,
+        selection: &dyn PeerSelection,
+        max_limit: i64,
+        time_now_ms: i64,
+    ) -> StoreResult<Vec<FamilyPeers>>;
+
+    /// The counters of each torrent, in the same order
+    async fn fetch_stats(&self, info_hashes: &[RawVal<40>]) -> StoreResult<Vec<TorrentStats>>;
+
+    async fn apply(&self, mutations: Vec<Mutation>) -> StoreResult<()>;
+
+    async fn ping(&self) -> StoreResult<()>;
+
+    /// The user id of `passkey` in the hash `key`, None if it isn't there (see `private`)
+    async fn fetch_user(&self, key: &str, passkey: &str) -> StoreResult<Option<String>>;
+
+    /// Add what the user transferred since the peer's previous report to their totals,
+    /// and keep this report (for `report_ttl_ms`) for the next one
+    async fn account(&self, report: private::Report, report_ttl_ms: i64) -> StoreResult<()>;
+
+    /// Every member of the set `key`, e.g. an allow/denylist (see `access`)
+    async fn fetch_set(&self, key: &str) -> StoreResult<Vec<Vec<u8>>>;
+
+    /// Start the peer's `interval_ms` for the torrent as `role`, unless one is still running for the same role:
+    /// then the ms left of it. For `rate_limit::Mode::Store`, so every kiryuu using the store sees the same intervals.
+    async fn throttle(&self, info_hash: &RawVal<40>, ip_port: &[u8], role: Role, time_now_ms: i64, interval_ms: i64) -> StoreResult<Option<i64>>;
+
+    /// Remove the peers that last announced before `peer_expiry`, fixing up the torrents' counters,
+    /// and drop the torrents (stats included) with no announce since `torrent_expiry`.
+    /// Stores that work through the torrents in steps do `batch_size` at a time.
+    async fn reap(&self, peer_expiry: i64, torrent_expiry: i64, batch_size: usize) -> StoreResult<ReapStats>;
+}
@@ -0,0 +1,39 @@
+-- Prunes one torrent, see `RedisStore::reap`
+-- KEYS: seeders, leechers, partial seeds, seeders6, leechers6, partial seeds6, stats hash, IPv6 mirrors, cache keys...
+-- ARGV: peer expiry, torrent expiry (ms), when it was added to TORRENTS
+-- Returns {peers removed, 1 if the whole torrent was dropped}
+
+-- Torrents from before we kept `last_active` in the stats hash go by when they were added
+local last_active = tonumber(redis.call('HGET', KEYS[7], 'last_active')) or tonumber(ARGV[3])
+
+local function drop_cache()
+    for i = 9, #KEYS do
+        redis.call('DEL', KEYS[i])
+    end
+end
+
+if last_active < tonumber(ARGV[2]) then
+    local peers = 0
+    for i = 1, 6 do
+        peers = peers + redis.call('ZCARD', KEYS[i])
+    end
+
+    redis.call('DEL', KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6], KEYS[7], KEYS[8])
+    drop_cache()
+    return {peers, 1}
+end
+
+local removed = 0
+for i = 1, 6 do
+    removed = removed + redis.call('ZREMRANGEBYSCORE', KEYS[i], '-inf', '(' .. ARGV[1])
+end
+-- Announced along with the mirrored peer, so they go stale together
+redis.call('ZREMRANGEBYSCORE', KEYS[8], '-inf', '(' .. ARGV[1])
+
+if removed > 0 then
+    -- Recount rather than decrement, which also heals any drift from announces racing each other
+    recount({KEYS[1], KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6]}, KEYS[8], KEYS[7])
+    drop_cache()
+end
+
+return {removed, 0}
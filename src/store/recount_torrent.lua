@@ -0,0 +1,21 @@
+-- Resets a torrent's counters from its sets, the way announces count peers: by their primary
+-- endpoint, so dual stack peers' IPv6 mirrors are left out. Prepended to the scripts that use it.
+-- sets: seeders, leechers, partial seeds, seeders6, leechers6, partial seeds6, mirrors: the IPv6 mirrors
+
+local function recount(sets, mirrors, stats)
+    local counts = {}
+    for i = 1, 3 do
+        counts[i] = redis.call('ZCARD', sets[i]) + redis.call('ZCARD', sets[3 + i])
+    end
+
+    for _, ip_port in ipairs(redis.call('ZRANGE', mirrors, 0, -1)) do
+        for i = 1, 3 do
+            if redis.call('ZSCORE', sets[3 + i], ip_port) then
+                counts[i] = counts[i] - 1
+            end
+        end
+    end
+
+    redis.call('HSET', stats, 'seeders', counts[1], 'leechers', counts[2], 'partial_seeds', counts[3])
+end
+
@@ -0,0 +1,999 @@
+use async_trait::async_trait;
+use futures::future;
+
+use crate::byte_functions::{self, types::RawVal};
+use crate::constants;
+use crate::peer_selection::PeerSelection;
+use crate::{private, query, swarm};
+
+use super::cluster::ClusterConnection;
+use super::sharding;
+use super::{Announced, Family, FamilyPeers, Mutation, ReapStats, Role, StoreResult, SwarmStore, TorrentStats};
+
+// In a cluster, the global keys in `constants` would each be a hot spot on one node.
+// So we spread them over this many keys: the stats (to be summed up by whoever reads them)
+// at random, and TORRENTS by the first hex char of the infohash.
+const CLUSTER_KEY_SHARDS: u8 = 16;
+
+// Drops a torrent from TORRENTS, unless it came back since we decided to drop it
+const FORGET_TORRENT: &str = "
+local added = tonumber(redis.call('ZSCORE', KEYS[1], ARGV[1]))
+if added and added < tonumber(ARGV[2]) then
+    return redis.call('ZREM', KEYS[1], ARGV[1])
+end
+return 0
+";
+
+// Starts the peer's interval as the role, unless one is still running for the same role: then returns its ms left
+const THROTTLE: &str = "
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return math.max(redis.call('PTTL', KEYS[1]), 1)
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return 0
+";
+
+#[derive(Clone)]
+pub enum RedisConnection {
+    /// Reconnects by itself, so redis can be restarted under us
+    Single(redis::aio::ConnectionManager),
+    Cluster(ClusterConnection),
+}
+
+impl redis::aio::ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> redis::RedisFuture<'a, redis::Value> {
+        return match self {
+            RedisConnection::Single(connection) => connection.req_packed_command(cmd),
+            RedisConnection::Cluster(connection) => connection.req_packed_command(cmd),
+        };
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a redis::Pipeline, offset: usize, count: usize) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        return match self {
+            RedisConnection::Single(connection) => connection.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+        };
+    }
+
+    fn get_db(&self) -> i64 {
+        return match self {
+            RedisConnection::Single(connection) => connection.get_db(),
+            RedisConnection::Cluster(connection) => connection.get_db(),
+        };
+    }
+}
+
+/// A torrent's peers live in a ZSET per (family, role), scored by their last announce.
+/// Its counters are in its stats hash. All its keys share the `{<infohash>}` hash tag.
+/// TORRENTS indexes every torrent (scored by when it was added), for the reaper.
+///
+/// With several shards, each torrent (keys, TORRENTS entry and all) lives on the one it hashes to.
+/// The global stats go to the first.
+pub struct RedisStore {
+    /// The shards' addresses, which is what torrents are hashed to (see `sharding`)
+    shard_names: Vec<String>,
+    shards: Vec<RedisConnection>,
+    /// Every numwant bucket we cache replies for, to invalidate them all
+    cache_numwants: Vec<u16>,
+    /// How long a cached reply lives, unless the torrent's peers change first
+    cache_ttl_secs: u32,
+    announce_script: redis::Script,
+    reap_script: redis::Script,
+    forget_torrent_script: redis::Script,
+    merge_torrent_script: redis::Script,
+    throttle_script: redis::Script,
+}
+
+/// What a `RedisStore::rebalance` did
+#[derive(Debug, Default)]
+pub struct RebalanceStats {
+    pub torrents: i64,
+    pub peers: i64,
+}
+
+impl RedisStore {
+    /// `shards` are (address, connection), there must be at least one
+    pub fn new(shards: Vec<(String, RedisConnection)>, cache_numwants: Vec<u16>, cache_ttl_secs: u32) -> RedisStore {
+        assert!(!shards.is_empty(), "RedisStore needs a shard");
+
+        return RedisStore {
+            shard_names: shards.iter().map(|(name, _)| name.clone()).collect(),
+            shards: shards.into_iter().map(|(_, connection)| connection).collect(),
+            cache_numwants,
+            cache_ttl_secs,
+            announce_script: redis::Script::new(include_str!("announce.lua")),
+            reap_script: redis::Script::new(concat!(include_str!("recount_torrent.lua"), include_str!("reap_torrent.lua"))),
+            forget_torrent_script: redis::Script::new(FORGET_TORRENT),
+            merge_torrent_script: redis::Script::new(concat!(include_str!("recount_torrent.lua"), include_str!("merge_torrent.lua"))),
+            throttle_script: redis::Script::new(THROTTLE),
+        };
+    }
+
+    fn shard_index(&self, info_hash: &RawVal<40>) -> usize {
+        return sharding::shard_for(&self.shard_names, info_hash);
+    }
+
+    /// The connection to the shard `info_hash` lives on
+    fn connection(&self, info_hash: &RawVal<40>) -> RedisConnection {
+        return self.shards[self.shard_index(info_hash)].clone();
+    }
+
+    fn cache_keys(&self, info_hash: &RawVal<40>) -> Vec<RawVal<52>> {
+        return self.cache_numwants.iter().map(|&numwant| byte_functions::make_cache_key(info_hash, numwant)).collect();
+    }
+
+    fn is_cluster(&self) -> bool {
+        return matches!(self.shards.first(), Some(RedisConnection::Cluster(_)));
+    }
+
+    /// The TORRENTS key the torrent is indexed in
+    fn torrents_key(&self, info_hash: &RawVal<40>) -> String {
+        return match self.is_cluster() {
+            true => format!("{}:{}", constants::TORRENTS_KEY, info_hash[0] as char),
+            false => constants::TORRENTS_KEY.to_string(),
+        };
+    }
+
+    fn all_torrents_keys(&self) -> Vec<String> {
+        return match self.is_cluster() {
+            true => (0..CLUSTER_KEY_SHARDS).map(|shard| format!("{}:{:x}", constants::TORRENTS_KEY, shard)).collect(),
+            false => vec![constants::TORRENTS_KEY.to_string()],
+        };
+    }
+
+    fn stat_key(&self, key: &str) -> String {
+        return match self.is_cluster() {
+            true => format!("{}:{}", key, rand::random::<u8>() % CLUSTER_KEY_SHARDS),
+            false => key.to_string(),
+        };
+    }
+
+    /// `announce.lua`'s KEYS & ARGV
+    fn announce_input(&self, parsed: &query::PeerInfo, numwant: u16, time_now_ms: i64) -> ScriptInput {
+        let info_hash = &parsed.info_hash;
+        let (primary, secondary) = swarm::endpoints(parsed);
+        let other_family = match primary.family {
+            Family::V4 => Family::V6,
+            Family::V6 => Family::V4,
+        };
+        let event = match parsed.event {
+            query::Event::Stopped => "stopped",
+            query::Event::Completed => "completed",
+            query::Event::Paused => "paused",
+            // Like any other announce, as far as the swarm goes
+            query::Event::Started | query::Event::Unknown => "",
+        };
+
+        let mut keys = vec![byte_functions::make_stats_key(info_hash).0.to_vec(), byte_functions::make_cache_key(info_hash, numwant).0.to_vec()];
+        for family in [primary.family, other_family] {
+            for role in swarm::ROLES {
+                keys.push(peers_key(info_hash, family, role));
+            }
+        }
+        keys.push(byte_functions::make_mirrors_key(info_hash).0.to_vec());
+        // In a cluster TORRENTS isn't in the torrent's slot, `Mutation::IndexTorrent` indexes it instead
+        if !self.is_cluster() {
+            keys.push(self.torrents_key(info_hash).into_bytes());
+        }
+        keys.extend(self.cache_keys(info_hash).into_iter().map(|key| key.0.to_vec()));
+
+        let args = vec![
+            time_now_ms.to_string().into_bytes(),
+            event.as_bytes().to_vec(),
+            if parsed.is_seeding { b"1".to_vec() } else { b"0".to_vec() },
+            primary.ip_port.to_vec(),
+            secondary.map_or(vec![], |endpoint| endpoint.ip_port.to_vec()),
+            if self.is_cluster() { vec![] } else { info_hash.0.to_vec() },
+        ];
+
+        return (keys, args);
+    }
+
+    /// After adding a shard, move each torrent that now hashes to another shard over to it.
+    /// Peers already announcing to the new shard are kept, the rest is merged in by `merge_torrent.lua`.
+    pub async fn rebalance(&self, batch_size: usize) -> StoreResult<RebalanceStats> {
+        let mut stats = RebalanceStats::default();
+
+        for (index, connection) in self.shards.iter().enumerate() {
+            let mut rc = connection.clone();
+
+            for torrents_key in self.all_torrents_keys() {
+                let mut cursor: u64 = 0;
+
+                loop {
+                    let (next, torrents) = scan_torrents(&mut rc, &torrents_key, cursor, batch_size).await?;
+
+                    for (info_hash, added) in torrents {
+                        let owner = self.shard_index(&info_hash);
+                        if owner != index {
+                            stats.peers += self.move_torrent(&mut rc, &torrents_key, &self.shards[owner], &info_hash, added).await?;
+                            stats.torrents += 1;
+                        }
+                    }
+
+                    if next == 0 {
+                        break;
+                    }
+
+                    cursor = next;
+                }
+            }
+        }
+
+        return Ok(stats);
+    }
+
+    /// Returns how many peers it moved
+    async fn move_torrent(&self, from: &mut RedisConnection, torrents_key: &str, to: &RedisConnection, info_hash: &RawVal<40>, added: i64) -> StoreResult<i64> {
+        let stats_key = byte_functions::make_stats_key(info_hash);
+        let mirrors_key = byte_functions::make_mirrors_key(info_hash);
+
+        let mut p = redis::pipe();
+        for (family, role) in ALL_SETS {
+            p.cmd("ZRANGE").arg(peers_key(info_hash, family, role)).arg(0).arg(-1).arg("WITHSCORES");
+        }
+        p.cmd("ZRANGE").arg(mirrors_key).arg(0).arg(-1).arg("WITHSCORES");
+        p.cmd("HMGET").arg(stats_key).arg("downloaded").arg("last_active");
+
+        let (s, l, p4, s6, l6, p6, mirrors, (downloaded, last_active)): MovedTorrent = p.query_async(from).await?;
+
+        let mut to = to.clone();
+        let mut p = redis::pipe();
+
+        let sets: Vec<(Vec<u8>, ScoredPeers)> = ALL_SETS.iter().map(|&(family, role)| peers_key(info_hash, family, role)).zip([s, l, p4, s6, l6, p6]).collect();
+        let peers = sets.iter().map(|(_, set)| set.len() as i64).sum();
+
+        for (key, set) in sets.into_iter().chain([(mirrors_key.0.to_vec(), mirrors)]) {
+            if set.is_empty() {
+                continue;
+            }
+
+            // GT: the peer may have announced to the new shard since
+            p.cmd("ZADD").arg(key).arg("GT");
+            for (ip_port, time_ms) in set {
+                p.arg(time_ms as i64).arg(ip_port);
+            }
+            p.ignore();
+        }
+        p.cmd("ZADD").arg(self.torrents_key(info_hash)).arg("NX").arg(added).arg(info_hash).ignore();
+        p.query_async::<_, ()>(&mut to).await?;
+
+        let mut invocation = self.merge_torrent_script.prepare_invoke();
+        for (family, role) in ALL_SETS {
+            invocation.key(peers_key(info_hash, family, role));
+        }
+        invocation.key(stats_key).key(mirrors_key).key(self.cache_keys(info_hash))
+        .arg(downloaded.unwrap_or(0)).arg(last_active.unwrap_or(added));
+        invocation.invoke_async::<_, ()>(&mut to).await?;
+
+        let mut p = redis::pipe();
+        p.cmd("DEL");
+        for (family, role) in ALL_SETS {
+            p.arg(peers_key(info_hash, family, role));
+        }
+        p.arg(stats_key).arg(mirrors_key).arg(self.cache_keys(info_hash)).ignore();
+        p.cmd("ZREM").arg(torrents_key).arg(info_hash).ignore();
+        p.query_async::<_, ()>(from).await?;
+
+        return Ok(peers);
+    }
+}
+
+// The order the scripts take the sets in as KEYS
+const ALL_SETS: [(Family, Role); 6] = [
+    (Family::V4, Role::Seeder),
+    (Family::V4, Role::Leecher),
+    (Family::V4, Role::PartialSeed),
+    (Family::V6, Role::Seeder),
+    (Family::V6, Role::Leecher),
+    (Family::V6, Role::PartialSeed),
+];
+
+fn peers_key(info_hash: &RawVal<40>, family: Family, role: Role) -> Vec<u8> {
+    return match (family, role) {
+        (Family::V4, Role::Seeder) => byte_functions::make_redis_keys(info_hash, 0).0.0.to_vec(),
+        (Family::V4, Role::Leecher) => byte_functions::make_redis_keys(info_hash, 0).1.0.to_vec(),
+        (Family::V4, Role::PartialSeed) => byte_functions::make_partial_seeds_keys(info_hash).0.0.to_vec(),
+        (Family::V6, Role::Seeder) => byte_functions::make_redis_keys6(info_hash).0.0.to_vec(),
+        (Family::V6, Role::Leecher) => byte_functions::make_redis_keys6(info_hash).1.0.to_vec(),
+        (Family::V6, Role::PartialSeed) => byte_functions::make_partial_seeds_keys(info_hash).1.0.to_vec(),
+    };
+}
+
+type ScoredPeers = Vec<(Vec<u8>, f64)>;
+/// Each of `ALL_SETS`, the mirrors, then the torrent's downloaded & last active
+type MovedTorrent = (ScoredPeers, ScoredPeers, ScoredPeers, ScoredPeers, ScoredPeers, ScoredPeers, ScoredPeers, (Option<i64>, Option<i64>));
+
+/// A script's (KEYS, ARGV)
+type ScriptInput = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+/// One ZSCAN step through a TORRENTS key, giving each torrent with when it was added
+async fn scan_torrents(rc: &mut RedisConnection, torrents_key: &str, cursor: u64, batch_size: usize) -> StoreResult<(u64, Vec<(RawVal<40>, i64)>)> {
+    let (next, torrents): (u64, Vec<(Vec<u8>, f64)>) = redis::cmd("ZSCAN").arg(torrents_key).arg(cursor).arg("COUNT").arg(batch_size)
+    .query_async(rc).await?;
+
+    let torrents = torrents.into_iter().filter_map(|(info_hash, added)| {
+        return <[u8; 40]>::try_from(info_hash).ok().map(|info_hash| (RawVal(info_hash), added as i64));
+    }).collect();
+
+    return Ok((next, torrents));
+}
+
+#[async_trait(?Send)]
+impl SwarmStore for RedisStore {
+    /// One round trip, running `announce.lua` (EVALSHA, loading it first if redis doesn't have it yet).
+    async fn announce(&self, parsed: &query::PeerInfo, numwant: u16, time_now_ms: i64) -> StoreResult<Announced> {
+        let info_hash = &parsed.info_hash;
+        let mut rc = self.connection(info_hash);
+
+        let (keys, args) = self.announce_input(parsed, numwant, time_now_ms);
+        let mut invocation = self.announce_script.prepare_invoke();
+        invocation.key(keys).arg(args);
+
+        let (seed_count_mod, leech_count_mod, partial_count_mod, cached_reply): (i64, i64, i64, Vec<u8>) = invocation.invoke_async(&mut rc).await?;
+
+        return Ok(Announced {
+            seed_count_mod,
+            leech_count_mod,
+            partial_count_mod,
+            cached_reply: match cached_reply.len() {
+                0 => None,
+                _ => Some(cached_reply),
+            },
+        });
+    }
+
+    async fn fetch_peers(
+        &self,
+        info_hash: &RawVal<40>,
+        families: &[Family],
+        numwant: u16,
+        selection: &dyn PeerSelection,
+        max_limit: i64,
+        time_now_ms: i64,
+    ) -> StoreResult<Vec<FamilyPeers>> {
+        let mut rc = self.connection(info_hash);
+        let mut p = redis::pipe();
+
+        for &family in families {
+            for role in swarm::ROLES {
+                selection.queue(&mut p, &peers_key(info_hash, family, role), numwant, max_limit, time_now_ms);
+            }
+        }
+
+        let values: Vec<redis::Value> = p.query_async(&mut rc).await?;
+        let mut result = Vec::with_capacity(families.len());
+
+        for family in values.chunks(3) {
+            result.push(FamilyPeers {
+                seeders: selection.peers(&family[0], numwant, max_limit)?,
+                leechers: selection.peers(&family[1], numwant, max_limit)?,
+                partial_seeds: selection.peers(&family[2], numwant, max_limit)?,
+            });
+        }
+
+        return Ok(result);
+    }
+
+    async fn fetch_stats(&self, info_hashes: &[RawVal<40>]) -> StoreResult<Vec<TorrentStats>> {
+        let mut pipes = vec![redis::pipe(); self.shards.len()];
+        let mut used = vec![false; self.shards.len()];
+        let owners: Vec<usize> = info_hashes.iter().map(|info_hash| self.shard_index(info_hash)).collect();
+
+        for (info_hash, &owner) in info_hashes.iter().zip(&owners) {
+            pipes[owner].cmd("HMGET").arg(byte_functions::make_stats_key(info_hash)).arg("seeders").arg("leechers").arg("partial_seeds").arg("downloaded");
+            used[owner] = true;
+        }
+
+        let replies = future::try_join_all(self.shards.iter().zip(pipes).zip(used).map(|((connection, p), used)| async move {
+            if !used {
+                return Ok(vec![]);
+            }
+
+            let mut rc = connection.clone();
+            let stats: Vec<(Option<i64>, Option<i64>, Option<i64>, Option<i64>)> = p.query_async(&mut rc).await?;
+            return Ok::<_, redis::RedisError>(stats);
+        })).await?;
+
+        // Back in the order asked for
+        let mut replies: Vec<_> = replies.into_iter().map(|stats| stats.into_iter()).collect();
+        let stats = owners.into_iter().map(|owner| replies[owner].next().unwrap_or_default());
+
+        // Counts can briefly dip below zero while announces race, don't hand that out
+        return Ok(stats.map(|(seeders, leechers, partial_seeds, downloaded)| TorrentStats {
+            seeders: seeders.unwrap_or(0).max(0),
+            leechers: leechers.unwrap_or(0).max(0),
+            partial_seeds: partial_seeds.unwrap_or(0).max(0),
+            downloaded: downloaded.unwrap_or(0).max(0),
+        }).collect());
+    }
+
+    async fn apply(&self, mutations: Vec<Mutation>) -> StoreResult<()> {
+        let mut pipes = vec![redis::pipe(); self.shards.len()];
+        let mut used = vec![false; self.shards.len()];
+
+        for mutation in mutations {
+            let shard = match &mutation {
+                Mutation::TouchTorrent { info_hash, .. }
+                | Mutation::UpsertPeer { info_hash, .. }
+                | Mutation::RemovePeer { info_hash, .. }
+                | Mutation::MarkMirror { info_hash, .. }
+                | Mutation::UnmarkMirror { info_hash, .. }
+                | Mutation::UpdateCounters { info_hash, .. }
+                | Mutation::InvalidateCache { info_hash }
+                | Mutation::CacheReply { info_hash, .. }
+                | Mutation::Unthrottle { info_hash, .. } => self.shard_index(info_hash),
+                Mutation::IncrementStat { .. } => 0,
+                // Already done by `announce.lua`, unless TORRENTS is in another slot than the torrent
+                Mutation::IndexTorrent { .. } if !self.is_cluster() => continue,
+                Mutation::IndexTorrent { info_hash, .. } => self.shard_index(info_hash),
+            };
+            let p = &mut pipes[shard];
+            used[shard] = true;
+
+            match mutation {
+                Mutation::TouchTorrent { info_hash, time_ms } => {
+                    p.cmd("HSET").arg(byte_functions::make_stats_key(&info_hash)).arg("last_active").arg(time_ms).ignore();
+                    p.cmd("ZADD").arg(self.torrents_key(&info_hash)).arg("NX").arg(time_ms).arg(info_hash).ignore();
+                },
+                Mutation::UpsertPeer { info_hash, family, role, ip_port, time_ms } => {
+                    p.cmd("ZADD").arg(peers_key(&info_hash, family, role)).arg(time_ms).arg(ip_port).ignore();
+                },
+                Mutation::RemovePeer { info_hash, family, role, ip_port } => {
+                    p.cmd("ZREM").arg(peers_key(&info_hash, family, role)).arg(ip_port).ignore();
+                },
+                Mutation::MarkMirror { info_hash, ip_port, time_ms } => {
+                    p.cmd("ZADD").arg(byte_functions::make_mirrors_key(&info_hash)).arg(time_ms).arg(ip_port).ignore();
+                },
+                Mutation::UnmarkMirror { info_hash, ip_port } => {
+                    p.cmd("ZREM").arg(byte_functions::make_mirrors_key(&info_hash)).arg(ip_port).ignore();
+                },
+                Mutation::UpdateCounters { info_hash, seeders, leechers, partial_seeds, downloaded } => {
+                    for (field, by) in [("seeders", seeders), ("leechers", leechers), ("partial_seeds", partial_seeds), ("downloaded", downloaded)] {
+                        if by != 0 {
+                            p.cmd("HINCRBY").arg(byte_functions::make_stats_key(&info_hash)).arg(field).arg(by).ignore();
+                        }
+                    }
+                },
+                Mutation::InvalidateCache { info_hash } => {
+                    p.cmd("DEL").arg(self.cache_keys(&info_hash)).ignore();
+                },
+                Mutation::CacheReply { info_hash, numwant, reply } => {
+                    p.cmd("SET").arg(byte_functions::make_cache_key(&info_hash, numwant)).arg(reply).arg("EX").arg(self.cache_ttl_secs).ignore();
+                },
+                Mutation::IncrementStat { key, by } => {
+                    p.cmd("INCRBY").arg(self.stat_key(key)).arg(by).ignore();
+                },
+                Mutation::Unthrottle { info_hash, ip_port } => {
+                    p.cmd("DEL").arg(byte_functions::make_throttle_key(&info_hash, &ip_port)).ignore();
+                },
+                Mutation::IndexTorrent { info_hash, time_ms } => {
+                    p.cmd("ZADD").arg(self.torrents_key(&info_hash)).arg("NX").arg(time_ms).arg(info_hash).ignore();
+                },
+            }
+        }
+
+        future::try_join_all(self.shards.iter().zip(pipes).zip(used).filter(|(_, used)| *used).map(|((connection, p), _)| async move {
+            let mut rc = connection.clone();
+            return p.query_async::<_, ()>(&mut rc).await;
+        })).await?;
+
+        return Ok(());
+    }
+
+    async fn ping(&self) -> StoreResult<()> {
+        future::try_join_all(self.shards.iter().map(|connection| async move {
+            let mut rc = connection.clone();
+            return redis::cmd("PING").query_async::<_, ()>(&mut rc).await;
+        })).await?;
+
+        return Ok(());
+    }
+
+    /// The passkeys hash lives on the first shard, like the global stats
+    async fn fetch_user(&self, key: &str, passkey: &str) -> StoreResult<Option<String>> {
+        let mut rc = self.shards[0].clone();
+        return Ok(redis::cmd("HGET").arg(key).arg(passkey).query_async(&mut rc).await?);
+    }
+
+    /// Swaps in the report on the torrent's shard (SET .. GET, or GETDEL for the last one), then adds the
+    /// difference to the user's `kiryuu_user:<id>` hash on the first shard: `uploaded` & `downloaded`,
+    /// and `<infohash>_uploaded` & `<infohash>_downloaded`
+    async fn account(&self, report: private::Report, report_ttl_ms: i64) -> StoreResult<()> {
+        let mut rc = self.connection(&report.info_hash);
+        let report_key = byte_functions::make_report_key(&report.info_hash, &report.user, &report.ip_port);
+
+        let previous: Option<String> = match report.stopped {
+            true => redis::cmd("GETDEL").arg(&report_key).query_async(&mut rc).await?,
+            false => redis::cmd("SET").arg(&report_key).arg(private::encode_report(&report)).arg("PX").arg(report_ttl_ms).arg("GET")
+            .query_async(&mut rc).await?,
+        };
+
+        let (uploaded, downloaded) = private::transfer_delta(previous.as_deref().and_then(private::decode_report), &report);
+        if uploaded == 0 && downloaded == 0 {
+            return Ok(());
+        }
+
+        let user_key = format!("kiryuu_user:{}", report.user);
+        let info_hash = String::from_utf8_lossy(&report.info_hash.0);
+        let mut p = redis::pipe();
+        for (field, by) in [
+            ("uploaded".to_string(), uploaded),
+            ("downloaded".to_string(), downloaded),
+            (format!("{}_uploaded", info_hash), uploaded),
+            (format!("{}_downloaded", info_hash), downloaded),
+        ] {
+            if by > 0 {
+                p.cmd("HINCRBY").arg(&user_key).arg(field).arg(by).ignore();
+            }
+        }
+
+        let mut rc = self.shards[0].clone();
+        p.query_async::<_, ()>(&mut rc).await?;
+
+        return Ok(());
+    }
+
+    /// SSCAN of the set on the first shard, like the global stats
+    async fn fetch_set(&self, key: &str) -> StoreResult<Vec<Vec<u8>>> {
+        let mut rc = self.shards[0].clone();
+        let mut members = Vec::new();
+        let mut cursor: u64 = 0;
+
+        loop {
+            let (next, batch): (u64, Vec<Vec<u8>>) = redis::cmd("SSCAN").arg(key).arg(cursor).arg("COUNT").arg(1000)
+            .query_async(&mut rc).await?;
+            members.extend(batch);
+
+            if next == 0 {
+                return Ok(members);
+            }
+
+            cursor = next;
+        }
+    }
+
+    /// The key (holding the role) only exists while the interval runs, and expires with it
+    async fn throttle(&self, info_hash: &RawVal<40>, ip_port: &[u8], role: Role, _time_now_ms: i64, interval_ms: i64) -> StoreResult<Option<i64>> {
+        let mut rc = self.connection(info_hash);
+        let role = match role {
+            Role::Seeder => "seeder",
+            Role::Leecher => "leecher",
+            Role::PartialSeed => "partial_seed",
+        };
+
+        let left: i64 = self.throttle_script.key(byte_functions::make_throttle_key(info_hash, ip_port)).arg(role).arg(interval_ms)
+        .invoke_async(&mut rc).await?;
+
+        return Ok(match left {
+            0 => None,
+            left => Some(left),
+        });
+    }
+
+    /// ZSCAN through each shard's TORRENTS, pruning each torrent atomically with `reap_torrent.lua`
+    async fn reap(&self, peer_expiry: i64, torrent_expiry: i64, batch_size: usize) -> StoreResult<ReapStats> {
+        let mut stats = ReapStats::default();
+
+        for connection in &self.shards {
+            let mut rc = connection.clone();
+
+            for torrents_key in self.all_torrents_keys() {
+                let mut cursor: u64 = 0;
+
+                loop {
+                    let (next, torrents) = scan_torrents(&mut rc, &torrents_key, cursor, batch_size).await?;
+
+                    for (info_hash, added) in torrents {
+                        let mut invocation = self.reap_script.prepare_invoke();
+                        for (family, role) in ALL_SETS {
+                            invocation.key(peers_key(&info_hash, family, role));
+                        }
+                        invocation.key(byte_functions::make_stats_key(&info_hash)).key(byte_functions::make_mirrors_key(&info_hash))
+                        .key(self.cache_keys(&info_hash))
+                        .arg(peer_expiry).arg(torrent_expiry).arg(added);
+
+                        let (peers, dropped): (i64, bool) = invocation.invoke_async(&mut rc).await?;
+                        stats.peers += peers;
+
+                        if dropped {
+                            stats.torrents += 1;
+                            let _: i64 = self.forget_torrent_script.key(&torrents_key).arg(info_hash).arg(torrent_expiry).invoke_async(&mut rc).await?;
+                        }
+                    }
+
+                    if next == 0 {
+                        break;
+                    }
+
+                    cursor = next;
+                }
+            }
+        }
+
+        return Ok(stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_selection;
+    use crate::store::{MemoryStore, PeerRole, Peers};
+
+    const INFO_HASH: RawVal<40> = RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+    const EVENTS: [fn() -> query::Event; 5] = [|| query::Event::Unknown, || query::Event::Started, || query::Event::Completed, || query::Event::Paused, || query::Event::Stopped];
+
+    /// A peer's (ip_port, ip6_port)
+    type Addresses = (Option<[u8; 6]>, Option<[u8; 18]>);
+
+    /// V4 only, V6 only, and dual stack
+    const ADDRESSES: [Addresses; 3] = [
+        (Some(*b"AAAAAA"), None),
+        (None, Some(*b"BBBBBBBBBBBBBBBBBB")),
+        (Some(*b"AAAAAA"), Some(*b"BBBBBBBBBBBBBBBBBB")),
+    ];
+
+    /// Redis as far as the scripts use it. Each key is a table: member => score for ZSETs,
+    /// field => value for hashes, and {value = ...} for strings.
+    const FAKE_REDIS: &str = "
+        local data = {}
+        redis = {}
+
+        function redis.call(command, key, ...)
+            local args = {...}
+            local value = data[key]
+
+            if command == 'GET' then
+                return value and value.value or false
+            elseif command == 'SET' then
+                data[key] = {value = args[1]}
+                return 'OK'
+            elseif command == 'DEL' then
+                data[key] = nil
+                return value and 1 or 0
+            elseif command == 'ZADD' or command == 'HSET' then
+                data[key] = value or {}
+                local nx = args[1] == 'NX'
+                if nx then
+                    table.remove(args, 1)
+                end
+                local member, score = args[2], args[1]
+                if command == 'HSET' then
+                    member, score = args[1], args[2]
+                end
+                local added = data[key][member] == nil and 1 or 0
+                if added == 1 or not nx then
+                    data[key][member] = score
+                end
+                return added
+            elseif command == 'ZREM' then
+                if not value or not value[args[1]] then
+                    return 0
+                end
+                value[args[1]] = nil
+                if next(value) == nil then
+                    data[key] = nil
+                end
+                return 1
+            elseif command == 'ZSCORE' then
+                return value and value[args[1]] or false
+            elseif command == 'HINCRBY' then
+                data[key] = value or {}
+                local by = (tonumber(data[key][args[1]]) or 0) + tonumber(args[2])
+                data[key][args[1]] = tostring(by)
+                return by
+            end
+
+            error('no ' .. command .. ' in the fake redis')
+        end
+
+        function members(key)
+            local members = {}
+            for member in pairs(data[key] or {}) do
+                table.insert(members, member)
+            end
+            table.sort(members)
+            return members
+        end
+
+        function field(key, name)
+            return tonumber((data[key] or {})[name]) or 0
+        end
+
+        function exists(key)
+            return data[key] ~= nil
+        end
+    ";
+
+    /// Runs the scripts on `FAKE_REDIS`, so they're tested without a redis
+    struct FakeRedis {
+        lua: mlua::Lua,
+    }
+
+    impl FakeRedis {
+        fn new() -> FakeRedis {
+            let lua = mlua::Lua::new();
+            lua.load(FAKE_REDIS).exec().unwrap();
+            return FakeRedis { lua };
+        }
+
+        fn call(&self, command: &str, key: &[u8], args: &[&[u8]]) {
+            let call: mlua::Function = self.lua.globals().get::<_, mlua::Table>("redis").unwrap().get("call").unwrap();
+            let mut values = vec![mlua::Value::String(self.lua.create_string(command).unwrap()), mlua::Value::String(self.lua.create_string(key).unwrap())];
+            values.extend(args.iter().map(|arg| mlua::Value::String(self.lua.create_string(arg).unwrap())));
+            call.call::<_, mlua::Value>(mlua::MultiValue::from_vec(values)).unwrap();
+        }
+
+        /// The mutations `prior_mutations` makes
+        fn apply(&self, store: &RedisStore, mutations: &[Mutation]) {
+            for mutation in mutations {
+                match mutation {
+                    Mutation::UpsertPeer { info_hash, family, role, ip_port, time_ms } => {
+                        self.call("ZADD", &peers_key(info_hash, *family, *role), &[time_ms.to_string().as_bytes(), ip_port]);
+                    },
+                    Mutation::MarkMirror { info_hash, ip_port, time_ms } => {
+                        self.call("ZADD", &byte_functions::make_mirrors_key(info_hash).0, &[time_ms.to_string().as_bytes(), ip_port]);
+                    },
+                    Mutation::UpdateCounters { info_hash, seeders, leechers, partial_seeds, downloaded } => {
+                        for (field, by) in [("seeders", seeders), ("leechers", leechers), ("partial_seeds", partial_seeds), ("downloaded", downloaded)] {
+                            self.call("HINCRBY", &byte_functions::make_stats_key(info_hash).0, &[field.as_bytes(), by.to_string().as_bytes()]);
+                        }
+                    },
+                    Mutation::CacheReply { info_hash, numwant, reply } => {
+                        self.call("SET", &byte_functions::make_cache_key(info_hash, *numwant).0, &[reply, store.cache_ttl_secs.to_string().as_bytes()]);
+                    },
+                    mutation => panic!("{:?} isn't in the fake redis", mutation),
+                }
+            }
+        }
+
+        /// `announce.lua`'s reply
+        fn announce(&self, store: &RedisStore, parsed: &query::PeerInfo, time_now_ms: i64) -> (i64, i64, i64, Vec<u8>) {
+            let (keys, args) = store.announce_input(parsed, 50, time_now_ms);
+            let globals = self.lua.globals();
+            globals.set("KEYS", self.lua.create_sequence_from(keys.iter().map(|key| self.lua.create_string(key).unwrap())).unwrap()).unwrap();
+            globals.set("ARGV", self.lua.create_sequence_from(args.iter().map(|arg| self.lua.create_string(arg).unwrap())).unwrap()).unwrap();
+
+            let reply: mlua::Table = self.lua.load(include_str!("announce.lua")).eval().unwrap();
+            return (
+                reply.get(1).unwrap(),
+                reply.get(2).unwrap(),
+                reply.get(3).unwrap(),
+                reply.get::<_, mlua::String>(4).unwrap().as_bytes().to_vec(),
+            );
+        }
+
+        /// Like `swarm_state`
+        fn swarm_state(&self) -> (Vec<Peers>, [i64; 4]) {
+            let members: mlua::Function = self.lua.globals().get("members").unwrap();
+            let field: mlua::Function = self.lua.globals().get("field").unwrap();
+
+            let sets = ALL_SETS.iter().map(|&(family, role)| {
+                let peers: Vec<mlua::String> = members.call(self.lua.create_string(peers_key(&INFO_HASH, family, role)).unwrap()).unwrap();
+                return peers.iter().map(|peer| peer.as_bytes().to_vec()).collect();
+            }).collect();
+
+            let stats_key = self.lua.create_string(byte_functions::make_stats_key(&INFO_HASH).0).unwrap();
+            let stats = ["seeders", "leechers", "partial_seeds", "downloaded"].map(|name| field.call::<_, i64>((stats_key.clone(), name)).unwrap());
+
+            return (sets, stats);
+        }
+
+        /// When the torrent was added to TORRENTS, 0 if it isn't there
+        fn indexed_at(&self, store: &RedisStore) -> i64 {
+            let field: mlua::Function = self.lua.globals().get("field").unwrap();
+            return field.call((store.torrents_key(&INFO_HASH), self.lua.create_string(INFO_HASH.0).unwrap())).unwrap();
+        }
+
+        fn exists(&self, key: &[u8]) -> bool {
+            let exists: mlua::Function = self.lua.globals().get("exists").unwrap();
+            return exists.call(self.lua.create_string(key).unwrap()).unwrap();
+        }
+    }
+
+    /// Enough to build the scripts' input, not to run them
+    fn offline_store() -> RedisStore {
+        return RedisStore {
+            shard_names: vec![],
+            shards: vec![],
+            cache_numwants: vec![50, 100],
+            cache_ttl_secs: 60,
+            announce_script: redis::Script::new(include_str!("announce.lua")),
+            reap_script: redis::Script::new(""),
+            forget_torrent_script: redis::Script::new(FORGET_TORRENT),
+            merge_torrent_script: redis::Script::new(""),
+            throttle_script: redis::Script::new(THROTTLE),
+        };
+    }
+
+    fn announce(addresses: Addresses, is_seeding: bool, event: query::Event) -> query::PeerInfo {
+        return query::PeerInfo { ip_port: addresses.0, ip6_port: addresses.1, info_hash: INFO_HASH, is_seeding, event, numwant: None, uploaded: 0, downloaded: 0 };
+    }
+
+    /// Every role a peer's primary endpoint can be in, `PeerRole`'s flags in every combination
+    fn prior_roles(addresses: Addresses) -> Vec<PeerRole> {
+        return (0..16).map(|flags| PeerRole {
+            is_seeder: flags & 1 != 0,
+            is_leecher: flags & 2 != 0,
+            is_partial_seed: flags & 4 != 0,
+            is_mirror: flags & 8 != 0,
+        })
+        // The primary endpoint is V4 if there's one, which is never a mirror
+        .filter(|role| !(role.is_mirror && addresses.0.is_some()))
+        .collect();
+    }
+
+    /// Puts the peer's primary endpoint in the role's sets (counted, unless it's a mirror), with a reply cached
+    fn prior_mutations(parsed: &query::PeerInfo, role: &PeerRole) -> Vec<Mutation> {
+        let (primary, _) = swarm::endpoints(parsed);
+        let mut mutations = vec![
+            Mutation::CacheReply { info_hash: INFO_HASH, numwant: 50, reply: b"cached".to_vec() },
+            Mutation::UpdateCounters {
+                info_hash: INFO_HASH,
+                seeders: role.counts_as(Role::Seeder) as i64,
+                leechers: role.counts_as(Role::Leecher) as i64,
+                partial_seeds: role.counts_as(Role::PartialSeed) as i64,
+                downloaded: 0,
+            },
+        ];
+
+        for counted in swarm::ROLES.into_iter().filter(|&counted| role.is(counted)) {
+            mutations.push(Mutation::UpsertPeer { info_hash: INFO_HASH, family: primary.family, role: counted, ip_port: primary.ip_port.to_vec(), time_ms: 50 });
+        }
+        if role.is_mirror {
+            mutations.push(Mutation::MarkMirror { info_hash: INFO_HASH, ip_port: primary.ip_port.to_vec(), time_ms: 50 });
+        }
+
+        return mutations;
+    }
+
+    /// The torrent's peers in each of `ALL_SETS`, and its counters
+    async fn swarm_state(store: &dyn SwarmStore) -> (Vec<Peers>, [i64; 4]) {
+        let selection = peer_selection::new(peer_selection::Strategy::Oldest);
+        let families = store.fetch_peers(&INFO_HASH, &[Family::V4, Family::V6], 50, selection.as_ref(), 0, 1000).await.unwrap();
+        let sets = families.into_iter().flat_map(|family| [family.seeders, family.leechers, family.partial_seeds]).map(|mut peers| {
+            peers.sort();
+            return peers;
+        }).collect();
+
+        let stats = &store.fetch_stats(&[INFO_HASH]).await.unwrap()[0];
+        return (sets, [stats.seeders, stats.leechers, stats.partial_seeds, stats.downloaded]);
+    }
+
+    /// `announce.lua` makes the same moves as `swarm::plan_announce` (which the memory store applies),
+    /// from every role the peer could have been in
+    #[actix_web::test]
+    async fn announce_script_follows_plan_announce() {
+        let store = offline_store();
+
+        for addresses in ADDRESSES {
+            for role in prior_roles(addresses) {
+                for event in EVENTS {
+                    for is_seeding in [false, true] {
+                        let parsed = announce(addresses, is_seeding, event());
+                        let case = format!("{:?} {:?} seeding: {} addresses: {:?}", role, parsed.event, is_seeding, addresses);
+                        let redis = FakeRedis::new();
+                        let memory = MemoryStore::new();
+
+                        let prior = prior_mutations(&parsed, &role);
+                        redis.apply(&store, &prior);
+                        memory.apply(prior).await.unwrap();
+
+                        let plan = swarm::plan_announce(&parsed, &role, swarm::CacheState::Miss, 100);
+                        let (seed_count_mod, leech_count_mod, partial_count_mod, cached_reply) = redis.announce(&store, &parsed, 100);
+                        assert_eq!((plan.seed_count_mod, plan.leech_count_mod, plan.partial_count_mod), (seed_count_mod, leech_count_mod, partial_count_mod), "{}", case);
+                        assert_eq!((b"cached".to_vec(), 100), (cached_reply, redis.indexed_at(&store)), "{}", case);
+
+                        memory.announce(&parsed, 50, 100).await.unwrap();
+                        assert_eq!(swarm_state(&memory).await, redis.swarm_state(), "{}", case);
+
+                        // The cached replies are dropped if the counts changed
+                        let changed = (seed_count_mod, leech_count_mod, partial_count_mod) != (0, 0, 0);
+                        assert_eq!(!changed, redis.exists(&byte_functions::make_cache_key(&INFO_HASH, 50).0), "{}", case);
+
+                        // Then the dual stack peer's mirror announces by itself, and should be counted once
+                        if let (Some(_), Some(ip6_port)) = addresses {
+                            let parsed = announce((None, Some(ip6_port)), false, query::Event::Unknown);
+
+                            let expected = memory.announce(&parsed, 50, 200).await.unwrap();
+                            let (seed_count_mod, leech_count_mod, partial_count_mod, _) = redis.announce(&store, &parsed, 200);
+                            assert_eq!(
+                                (expected.seed_count_mod, expected.leech_count_mod, expected.partial_count_mod),
+                                (seed_count_mod, leech_count_mod, partial_count_mod),
+                                "{}, then its mirror", case,
+                            );
+                            // Still indexed as of when it was added
+                            assert_eq!(100, redis.indexed_at(&store), "{}, then its mirror", case);
+                            assert_eq!(swarm_state(&memory).await, redis.swarm_state(), "{}, then its mirror", case);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn forget_torrent(store: &RedisStore) {
+        let mut p = redis::pipe();
+        p.cmd("DEL");
+        for (family, role) in ALL_SETS {
+            p.arg(peers_key(&INFO_HASH, family, role));
+        }
+        p.arg(byte_functions::make_stats_key(&INFO_HASH)).arg(byte_functions::make_mirrors_key(&INFO_HASH)).arg(store.cache_keys(&INFO_HASH)).ignore();
+        p.cmd("ZREM").arg(store.torrents_key(&INFO_HASH)).arg(INFO_HASH).ignore();
+        p.query_async::<_, ()>(&mut store.connection(&INFO_HASH)).await.unwrap();
+    }
+
+    /// Checks `announce.lua` against the memory store, from every role the peer could have been in.
+    /// Needs a redis to run against, whose address is in `KIRYUU_TEST_REDIS` (e.g. redis://127.0.0.1),
+    /// otherwise it's skipped. Don't point it at a redis you care about, it deletes the test torrent's keys.
+    #[actix_web::test]
+    async fn announces_like_the_memory_store() {
+        let address = match std::env::var("KIRYUU_TEST_REDIS") {
+            Ok(address) => address,
+            Err(_) => {
+                println!("KIRYUU_TEST_REDIS isn't set, skipping");
+                return;
+            },
+        };
+
+        let client = redis::Client::open(address.as_str()).unwrap();
+        let connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+        let store = RedisStore::new(vec![(address, RedisConnection::Single(connection))], vec![50, 100], 60);
+
+        for addresses in ADDRESSES {
+            for role in prior_roles(addresses) {
+                for event in EVENTS {
+                    for is_seeding in [false, true] {
+                        let parsed = announce(addresses, is_seeding, event());
+
+                        let case = format!("{:?} {:?} seeding: {} addresses: {:?}", role, parsed.event, is_seeding, addresses);
+                        let memory = MemoryStore::new();
+                        forget_torrent(&store).await;
+
+                        for swarm in [&memory as &dyn SwarmStore, &store] {
+                            swarm.apply(prior_mutations(&parsed, &role)).await.unwrap();
+                        }
+
+                        let expected = memory.announce(&parsed, 50, 100).await.unwrap();
+                        let announced = store.announce(&parsed, 50, 100).await.unwrap();
+                        assert_eq!(
+                            (expected.seed_count_mod, expected.leech_count_mod, expected.partial_count_mod),
+                            (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod),
+                            "{}", case,
+                        );
+                        assert_eq!(swarm_state(&memory).await, swarm_state(&store).await, "{}", case);
+
+                        // The memory store doesn't cache, so check ours by itself: handed out, then dropped if the counts changed
+                        let changed = (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod) != (0, 0, 0);
+                        let cached: Option<Vec<u8>> = redis::cmd("GET").arg(byte_functions::make_cache_key(&INFO_HASH, 50))
+                        .query_async(&mut store.connection(&INFO_HASH)).await.unwrap();
+                        assert_eq!(Some(b"cached".to_vec()), announced.cached_reply, "{}", case);
+                        assert_eq!(changed, cached.is_none(), "{}", case);
+
+                        // Indexed for the reaper by the same script
+                        let indexed_at: Option<i64> = redis::cmd("ZSCORE").arg(store.torrents_key(&INFO_HASH)).arg(INFO_HASH)
+                        .query_async(&mut store.connection(&INFO_HASH)).await.unwrap();
+                        assert_eq!(Some(100), indexed_at, "{}", case);
+
+                        // Then the dual stack peer's mirror announces by itself, and should be counted once
+                        if let (Some(_), Some(ip6_port)) = addresses {
+                            let parsed = announce((None, Some(ip6_port)), false, query::Event::Unknown);
+
+                            let expected = memory.announce(&parsed, 50, 200).await.unwrap();
+                            let announced = store.announce(&parsed, 50, 200).await.unwrap();
+                            assert_eq!(
+                                (expected.seed_count_mod, expected.leech_count_mod, expected.partial_count_mod),
+                                (announced.seed_count_mod, announced.leech_count_mod, announced.partial_count_mod),
+                                "{}, then its mirror", case,
+                            );
+                            assert_eq!(swarm_state(&memory).await, swarm_state(&store).await, "{}, then its mirror", case);
+                        }
+                    }
+                }
+            }
+        }
+
+        forget_torrent(&store).await;
+    }
+}
@@ -0,0 +1,89 @@
+// Client side sharding over several independent redis instances (see `RedisStore`).
+// Each torrent lives on one of them, picked with rendezvous hashing: every shard
+// scores the infohash, and the highest score wins. Adding a shard only moves the
+// torrents it now wins, and the order the shards are listed in doesn't matter.
+
+use crate::byte_functions::types::RawVal;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a spreads short, similar inputs (like `host:port`s) badly in its high bits,
+// so the score gets mixed with splitmix64's finalizer
+fn score(shard: &str, info_hash: &RawVal<40>) -> u64 {
+    let mut hash = FNV_OFFSET;
+
+    for &byte in shard.as_bytes().iter().chain(&[0xff]).chain(info_hash.0.iter()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return hash ^ (hash >> 31);
+}
+
+/// Index of the shard (named by its address) `info_hash` lives on
+pub fn shard_for(shards: &[String], info_hash: &RawVal<40>) -> usize {
+    if shards.len() == 1 {
+        return 0;
+    }
+
+    return shards.iter().enumerate().max_by_key(|(_, shard)| score(shard, info_hash)).map_or(0, |(index, _)| index);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info_hash(n: u32) -> RawVal<40> {
+        let mut info_hash = [b'0'; 40];
+        info_hash[32..].copy_from_slice(format!("{:08x}", n).as_bytes());
+        return RawVal(info_hash);
+    }
+
+    fn shards(n: usize) -> Vec<String> {
+        return (0..n).map(|i| format!("10.0.0.{}:6379", i)).collect();
+    }
+
+    #[test]
+    fn spreads_torrents_evenly() {
+        let shards = shards(4);
+        let mut counts = [0; 4];
+
+        for n in 0..4000 {
+            counts[shard_for(&shards, &info_hash(n))] += 1;
+        }
+
+        for count in counts {
+            assert!((800..1200).contains(&count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn ignores_shard_order() {
+        let shards = shards(3);
+        let reversed: Vec<String> = shards.iter().rev().cloned().collect();
+
+        for n in 0..100 {
+            assert_eq!(shards[shard_for(&shards, &info_hash(n))], reversed[shard_for(&reversed, &info_hash(n))]);
+        }
+    }
+
+    #[test]
+    fn only_moves_torrents_to_a_new_shard() {
+        let before = shards(3);
+        let after = shards(4);
+        let mut moved = 0;
+
+        for n in 0..1000 {
+            let (old, new) = (shard_for(&before, &info_hash(n)), shard_for(&after, &info_hash(n)));
+            if old != new {
+                assert_eq!(new, 3);
+                moved += 1;
+            }
+        }
+
+        assert!((150..350).contains(&moved), "{}", moved);
+    }
+}
@@ -0,0 +1,633 @@
+// How an announce changes the swarm, and where its reply comes from (see `plan_announce`).
+// Pure, no store in here: the stores apply the mutations, and the handlers follow the reply plan.
+
+use crate::byte_functions::types::RawVal;
+use crate::query;
+
+// If not more than 31, possible not online
+// So dont waste bandwidth on redis query etc.
+// The default `--peer-window`.
+pub const THIRTY_ONE_MINUTES: i64 = 60 * 31 * 1000;
+
+// BEP 15: "Up to about 74 torrents can be scraped at once".
+// We use the same limit for HTTP scrapes.
+pub const MAX_SCRAPE_TORRENTS: usize = 74;
+
+// BEP 3: "If omitted, typically defaults to 50 peers". The default `--default-numwant`.
+pub const DEFAULT_NUMWANT: u16 = 50;
+
+// The peer list sizes we build (and cache) replies for, on top of the configured max.
+// Keeping these few means we know every cache key a torrent can have when invalidating.
+const NUMWANT_BUCKETS: [u16; 6] = [0, 10, 25, 50, 100, 200];
+
+/// Maps a client's `numwant` to one of a few reply sizes
+pub struct NumwantBuckets {
+    buckets: Vec<u16>,
+    /// For clients that don't ask for a number
+    default_numwant: u16,
+}
+
+impl NumwantBuckets {
+    pub fn new(max_numwant: u16, default_numwant: u16) -> NumwantBuckets {
+        let mut buckets: Vec<u16> = NUMWANT_BUCKETS.iter().copied().filter(|&bucket| bucket < max_numwant).collect();
+        buckets.push(max_numwant);
+
+        return NumwantBuckets { buckets, default_numwant };
+    }
+
+    pub fn max(&self) -> u16 {
+        return self.buckets[self.buckets.len() - 1];
+    }
+
+    /// Clamp the requested number of peers to the max, and round it up to the nearest bucket.
+    pub fn bucket(&self, numwant: Option<u32>) -> u16 {
+        let wanted = numwant.unwrap_or(self.default_numwant as u32).min(self.max() as u32) as u16;
+        return *self.buckets.iter().find(|&&bucket| bucket >= wanted).expect("max is always a bucket");
+    }
+
+    /// Every reply size, i.e. each cache key the torrent can have
+    pub fn all(&self) -> Vec<u16> {
+        return self.buckets.clone();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    Seeder,
+    Leecher,
+    /// BEP 21, see `query::Event::Paused`
+    PartialSeed,
+}
+
+pub const ROLES: [Role; 3] = [Role::Seeder, Role::Leecher, Role::PartialSeed];
+
+/// Which of the torrent's sets the peer is already in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerRole {
+    pub is_seeder: bool,
+    pub is_leecher: bool,
+    pub is_partial_seed: bool,
+    /// It's only in them as a dual stack peer's mirror (see `mirror_announce`), so it isn't counted
+    pub is_mirror: bool,
+}
+
+impl PeerRole {
+    pub fn is(&self, role: Role) -> bool {
+        return match role {
+            Role::Seeder => self.is_seeder,
+            Role::Leecher => self.is_leecher,
+            Role::PartialSeed => self.is_partial_seed,
+        };
+    }
+
+    /// Whether it's in the torrent's count of `role`
+    pub fn counts_as(&self, role: Role) -> bool {
+        return self.is(role) && !self.is_mirror;
+    }
+}
+
+/// A change to the swarms. These are applied in one go (for redis, a single pipeline).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mutation {
+    /// Mark the torrent as active
+    TouchTorrent { info_hash: RawVal<40>, time_ms: i64 },
+    UpsertPeer { info_hash: RawVal<40>, family: Family, role: Role, ip_port: Vec<u8>, time_ms: i64 },
+    RemovePeer { info_hash: RawVal<40>, family: Family, role: Role, ip_port: Vec<u8> },
+    /// The IPv6 endpoint is a dual stack peer's mirror, see `PeerRole::is_mirror`
+    MarkMirror { info_hash: RawVal<40>, ip_port: Vec<u8>, time_ms: i64 },
+    UnmarkMirror { info_hash: RawVal<40>, ip_port: Vec<u8> },
+    /// Add to the torrent's counters
+    UpdateCounters { info_hash: RawVal<40>, seeders: i64, leechers: i64, partial_seeds: i64, downloaded: i64 },
+    /// Drop the torrent's cached replies, since its peers changed
+    InvalidateCache { info_hash: RawVal<40> },
+    CacheReply { info_hash: RawVal<40>, numwant: u16, reply: Vec<u8> },
+    /// Bump one of the global stats in `constants`
+    IncrementStat { key: &'static str, by: i64 },
+    /// End the peer's announce interval for the torrent early, see `SwarmStore::throttle`
+    Unthrottle { info_hash: RawVal<40>, ip_port: Vec<u8> },
+    /// Make sure the torrent is indexed for the reaper, after every announce.
+    /// For stores that can't do it as part of `SwarmStore::announce`.
+    IndexTorrent { info_hash: RawVal<40>, time_ms: i64 },
+}
+
+/// A peer's compact address, and which family's sets it goes in
+pub struct Endpoint<'a> {
+    pub family: Family,
+    pub ip_port: &'a [u8],
+}
+
+/// The peer's endpoints. The primary one (IPv4 if the peer has one, else IPv6)
+/// is what we track the peer's role & the seeder / leecher counts by.
+/// The secondary one (dual stack peers, always IPv6) just mirrors it into the other family's sets.
+pub fn endpoints(parsed: &query::PeerInfo) -> (Endpoint<'_>, Option<Endpoint<'_>>) {
+    let v6 = parsed.ip6_port.as_ref().map(|ip6_port| Endpoint { family: Family::V6, ip_port: ip6_port });
+
+    return match parsed.ip_port {
+        Some(ref ip_port) => (Endpoint { family: Family::V4, ip_port }, v6),
+        None => (v6.expect("PeerInfo without any address"), None),
+    };
+}
+
+fn upsert(parsed: &query::PeerInfo, endpoint: &Endpoint, role: Role, time_now_ms: i64) -> Mutation {
+    return Mutation::UpsertPeer { info_hash: parsed.info_hash, family: endpoint.family, role, ip_port: endpoint.ip_port.to_vec(), time_ms: time_now_ms };
+}
+
+fn remove(parsed: &query::PeerInfo, endpoint: &Endpoint, role: Role) -> Mutation {
+    return Mutation::RemovePeer { info_hash: parsed.info_hash, family: endpoint.family, role, ip_port: endpoint.ip_port.to_vec() };
+}
+
+/// The set the peer belongs in after the announce, None if it stopped
+pub fn role_after(parsed: &query::PeerInfo) -> Option<Role> {
+    return match parsed.event {
+        query::Event::Stopped => None,
+        _ if parsed.is_seeding => Some(Role::Seeder),
+        _ if parsed.is_partial_seed() => Some(Role::PartialSeed),
+        _ => Some(Role::Leecher),
+    };
+}
+
+/// Queue up the changes to record this announce in the swarm,
+/// given which sets the peer's primary endpoint was already in.
+/// The redis store does the same thing server side (`store/announce.lua`), its tests check they agree.
+///
+/// The peer ends up in exactly one set (none if it stopped), whatever it was in before:
+/// a leecher that reports `left=0` becomes a seeder with or without `completed`,
+/// a seeder that reports `left>0` (e.g. new files in the torrent) is a leecher again,
+/// and a leecher that announces `paused` is a partial seed until it announces without it.
+///
+/// Returns how the total number of seeders / leechers / partial seeds changes as a result.
+fn record_announce(
+    mutations: &mut Vec<Mutation>,
+    parsed: &query::PeerInfo,
+    role: &PeerRole,
+    time_now_ms: i64,
+) -> (i64, i64, i64) {
+    mutations.push(Mutation::TouchTorrent { info_hash: parsed.info_hash, time_ms: time_now_ms }); // To "update" the torrent
+
+    let (primary, secondary) = endpoints(parsed);
+    let after = role_after(parsed);
+
+    if let Some(ref endpoint) = secondary {
+        mirror_announce(mutations, parsed, endpoint, after, time_now_ms);
+    }
+
+    // An IPv6 peer that used to be a dual stack one's mirror is counted from now on
+    if role.is_mirror {
+        mutations.push(Mutation::UnmarkMirror { info_hash: parsed.info_hash, ip_port: primary.ip_port.to_vec() });
+    }
+
+    // These will contain how we change the total number of seeders / leechers / partial seeds by the end of the announce
+    let count_mod = |counted: Role| (after == Some(counted)) as i64 - role.counts_as(counted) as i64;
+
+    for counted in ROLES {
+        // Upsert it regardless to update timestamp for the guy
+        if after == Some(counted) {
+            mutations.push(upsert(parsed, &primary, counted, time_now_ms));
+        } else if role.is(counted) {
+            mutations.push(remove(parsed, &primary, counted));
+        }
+    }
+
+    // They just completed, increment the downloaded count for the infohash stats
+    if let (query::Event::Completed, true) = (&parsed.event, parsed.is_seeding) {
+        mutations.push(Mutation::UpdateCounters { info_hash: parsed.info_hash, seeders: 0, leechers: 0, partial_seeds: 0, downloaded: 1 });
+    }
+
+    return (count_mod(Role::Seeder), count_mod(Role::Leecher), count_mod(Role::PartialSeed));
+}
+
+/// Apply the announce to a dual stack peer's secondary endpoint. We don't look up its role,
+/// so remove unconditionally where we would have checked first, and leave the counts alone.
+/// It's marked as a mirror, so recounting the sets (see `SwarmStore::reap`) leaves it out too.
+fn mirror_announce(mutations: &mut Vec<Mutation>, parsed: &query::PeerInfo, endpoint: &Endpoint, after: Option<Role>, time_now_ms: i64) {
+    for role in ROLES {
+        if after == Some(role) {
+            mutations.push(upsert(parsed, endpoint, role, time_now_ms));
+        } else {
+            mutations.push(remove(parsed, endpoint, role));
+        }
+    }
+
+    mutations.push(match after {
+        Some(_) => Mutation::MarkMirror { info_hash: parsed.info_hash, ip_port: endpoint.ip_port.to_vec(), time_ms: time_now_ms },
+        None => Mutation::UnmarkMirror { info_hash: parsed.info_hash, ip_port: endpoint.ip_port.to_vec() },
+    });
+}
+
+/// If the announce changed the number of seeders / leechers / partial seeds, queue up the
+/// counters update and invalidate the cached replies for the torrent.
+///
+/// Returns whether there was a change.
+fn apply_count_mods(
+    mutations: &mut Vec<Mutation>,
+    info_hash: &RawVal<40>,
+    seed_count_mod: i64,
+    leech_count_mod: i64,
+    partial_count_mod: i64,
+) -> bool {
+    if seed_count_mod == 0 && leech_count_mod == 0 && partial_count_mod == 0 {
+        return false;
+    }
+
+    mutations.push(Mutation::UpdateCounters { info_hash: *info_hash, seeders: seed_count_mod, leechers: leech_count_mod, partial_seeds: partial_count_mod, downloaded: 0 });
+
+    // TODO: Patch cached reply with the count mods?
+    // Also invalidate existing cache
+    mutations.push(Mutation::InvalidateCache { info_hash: *info_hash });
+
+    return true;
+}
+
+/// Whether the store had a reply cached for the torrent (at the announce's numwant) before the announce
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheState {
+    Hit,
+    Miss,
+}
+
+/// Where the announce reply's peers & counts come from
+#[derive(Debug, PartialEq)]
+pub enum ReplySource {
+    /// The cached peers. The cached counts don't have this announce yet, so add these
+    Cached { seed_count_mod: i64, leech_count_mod: i64, partial_count_mod: i64 },
+    /// Fetch the peers from the store. That's after the announce went in, so the counts already have it
+    Fetch,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReplyPlan {
+    pub source: ReplySource,
+    /// Cache the reply's peers afterwards. Only while the counts are unchanged,
+    /// otherwise the announce just invalidated the cache
+    pub cache: bool,
+}
+
+/// Everything one announce does
+#[derive(Debug, PartialEq)]
+pub struct AnnouncePlan {
+    /// Records the announce, for the store to apply atomically
+    pub mutations: Vec<Mutation>,
+    pub seed_count_mod: i64,
+    pub leech_count_mod: i64,
+    pub partial_count_mod: i64,
+    pub reply: ReplyPlan,
+}
+
+/// The announce state machine: given which sets the peer was in before the announce
+/// and the torrent's cache, how the swarm changes and how to reply.
+pub fn plan_announce(parsed: &query::PeerInfo, role: &PeerRole, cache: CacheState, time_now_ms: i64) -> AnnouncePlan {
+    let mut mutations = Vec::new();
+    let (seed_count_mod, leech_count_mod, partial_count_mod) = record_announce(&mut mutations, parsed, role, time_now_ms);
+    apply_count_mods(&mut mutations, &parsed.info_hash, seed_count_mod, leech_count_mod, partial_count_mod);
+
+    return AnnouncePlan {
+        mutations,
+        seed_count_mod,
+        leech_count_mod,
+        partial_count_mod,
+        reply: plan_reply(seed_count_mod, leech_count_mod, partial_count_mod, cache),
+    };
+}
+
+/// The reply half of `plan_announce`, for when the store made the mutations itself (`store/announce.lua`)
+/// and only hands back the count mods
+pub fn plan_reply(seed_count_mod: i64, leech_count_mod: i64, partial_count_mod: i64, cache: CacheState) -> ReplyPlan {
+    let source = match cache {
+        CacheState::Hit => ReplySource::Cached { seed_count_mod, leech_count_mod, partial_count_mod },
+        CacheState::Miss => ReplySource::Fetch,
+    };
+
+    return ReplyPlan { source, cache: seed_count_mod == 0 && leech_count_mod == 0 && partial_count_mod == 0 };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use proptest::prelude::*;
+
+    #[test]
+    fn buckets_numwant() {
+        let buckets = NumwantBuckets::new(150, DEFAULT_NUMWANT);
+        assert_eq!(150, buckets.max());
+        assert_eq!(50, buckets.bucket(None));
+        assert_eq!(0, buckets.bucket(Some(0)));
+        assert_eq!(10, buckets.bucket(Some(1)));
+        assert_eq!(50, buckets.bucket(Some(30)));
+        assert_eq!(100, buckets.bucket(Some(100)));
+        assert_eq!(150, buckets.bucket(Some(101)));
+        assert_eq!(150, buckets.bucket(Some(100000)));
+        assert_eq!(vec![0, 10, 25, 50, 100, 150], buckets.all());
+
+        // Default is clamped to the max too
+        assert_eq!(20, NumwantBuckets::new(20, DEFAULT_NUMWANT).bucket(None));
+    }
+
+    #[test]
+    fn records_completed_leecher() {
+        let parsed = query::PeerInfo {
+            ip_port: Some(*b"AAAAAA"),
+            ip6_port: None,
+            info_hash: RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+            is_seeding: true,
+            event: query::Event::Completed,
+            numwant: None,
+            uploaded: 0,
+            downloaded: 0,
+        };
+
+        let mut mutations = Vec::new();
+        let mods = record_announce(&mut mutations, &parsed, &PeerRole { is_seeder: false, is_leecher: true, is_partial_seed: false, is_mirror: false }, 100);
+        assert_eq!((1, -1, 0), mods);
+        assert_eq!(vec![
+            Mutation::TouchTorrent { info_hash: parsed.info_hash, time_ms: 100 },
+            Mutation::UpsertPeer { info_hash: parsed.info_hash, family: Family::V4, role: Role::Seeder, ip_port: b"AAAAAA".to_vec(), time_ms: 100 },
+            Mutation::RemovePeer { info_hash: parsed.info_hash, family: Family::V4, role: Role::Leecher, ip_port: b"AAAAAA".to_vec() },
+            Mutation::UpdateCounters { info_hash: parsed.info_hash, seeders: 0, leechers: 0, partial_seeds: 0, downloaded: 1 },
+        ], mutations);
+
+        assert!(apply_count_mods(&mut mutations, &parsed.info_hash, mods.0, mods.1, mods.2));
+        assert_eq!(Some(&Mutation::InvalidateCache { info_hash: parsed.info_hash }), mutations.last());
+        assert!(!apply_count_mods(&mut mutations, &parsed.info_hash, 0, 0, 0));
+    }
+
+    /// Which sets `ip_port` is in after the mutations, from `before`, and the downloaded count they add
+    fn replay(mutations: &[Mutation], family: Family, ip_port: &[u8], before: PeerRole) -> (PeerRole, i64) {
+        let (mut sets, mut downloaded) = (before, 0);
+
+        for mutation in mutations {
+            let (role, is_in) = match mutation {
+                Mutation::UpsertPeer { family: f, role, ip_port: p, .. } if *f == family && p == ip_port => (role, true),
+                Mutation::RemovePeer { family: f, role, ip_port: p, .. } if *f == family && p == ip_port => (role, false),
+                Mutation::UpdateCounters { downloaded: d, .. } => {
+                    downloaded += d;
+                    continue;
+                },
+                _ => continue,
+            };
+
+            match role {
+                Role::Seeder => sets.is_seeder = is_in,
+                Role::Leecher => sets.is_leecher = is_in,
+                Role::PartialSeed => sets.is_partial_seed = is_in,
+            }
+        }
+
+        return (sets, downloaded);
+    }
+
+    const NONE: PeerRole = PeerRole { is_seeder: false, is_leecher: false, is_partial_seed: false, is_mirror: false };
+    const SEEDER: PeerRole = PeerRole { is_seeder: true, is_leecher: false, is_partial_seed: false, is_mirror: false };
+    const LEECHER: PeerRole = PeerRole { is_seeder: false, is_leecher: true, is_partial_seed: false, is_mirror: false };
+    const PARTIAL: PeerRole = PeerRole { is_seeder: false, is_leecher: false, is_partial_seed: true, is_mirror: false };
+    // Both sets, left over from before transitions were handled
+    const BOTH: PeerRole = PeerRole { is_seeder: true, is_leecher: true, is_partial_seed: false, is_mirror: false };
+
+    #[test]
+    fn moves_peers_between_roles() {
+        // (in the sets before, event, left=0) => (seed, leech, partial count mods), in the sets after
+        let cases = [
+            (NONE, query::Event::Unknown, false, (0, 1, 0), LEECHER),
+            (NONE, query::Event::Unknown, true, (1, 0, 0), SEEDER),
+            (NONE, query::Event::Started, false, (0, 1, 0), LEECHER),
+            (NONE, query::Event::Started, true, (1, 0, 0), SEEDER),
+            (NONE, query::Event::Completed, false, (0, 1, 0), LEECHER),
+            (NONE, query::Event::Completed, true, (1, 0, 0), SEEDER),
+            (NONE, query::Event::Paused, false, (0, 0, 1), PARTIAL),
+            (NONE, query::Event::Paused, true, (1, 0, 0), SEEDER),
+            (NONE, query::Event::Stopped, false, (0, 0, 0), NONE),
+            (NONE, query::Event::Stopped, true, (0, 0, 0), NONE),
+            (SEEDER, query::Event::Unknown, false, (-1, 1, 0), LEECHER),
+            (SEEDER, query::Event::Unknown, true, (0, 0, 0), SEEDER),
+            (SEEDER, query::Event::Started, false, (-1, 1, 0), LEECHER),
+            (SEEDER, query::Event::Started, true, (0, 0, 0), SEEDER),
+            (SEEDER, query::Event::Completed, false, (-1, 1, 0), LEECHER),
+            (SEEDER, query::Event::Completed, true, (0, 0, 0), SEEDER),
+            (SEEDER, query::Event::Paused, false, (-1, 0, 1), PARTIAL),
+            (SEEDER, query::Event::Paused, true, (0, 0, 0), SEEDER),
+            (SEEDER, query::Event::Stopped, false, (-1, 0, 0), NONE),
+            (SEEDER, query::Event::Stopped, true, (-1, 0, 0), NONE),
+            (LEECHER, query::Event::Unknown, false, (0, 0, 0), LEECHER),
+            (LEECHER, query::Event::Unknown, true, (1, -1, 0), SEEDER),
+            (LEECHER, query::Event::Started, false, (0, 0, 0), LEECHER),
+            (LEECHER, query::Event::Started, true, (1, -1, 0), SEEDER),
+            (LEECHER, query::Event::Completed, false, (0, 0, 0), LEECHER),
+            (LEECHER, query::Event::Completed, true, (1, -1, 0), SEEDER),
+            (LEECHER, query::Event::Paused, false, (0, -1, 1), PARTIAL),
+            (LEECHER, query::Event::Paused, true, (1, -1, 0), SEEDER),
+            (LEECHER, query::Event::Stopped, false, (0, -1, 0), NONE),
+            (LEECHER, query::Event::Stopped, true, (0, -1, 0), NONE),
+            (PARTIAL, query::Event::Unknown, false, (0, 1, -1), LEECHER),
+            (PARTIAL, query::Event::Unknown, true, (1, 0, -1), SEEDER),
+            (PARTIAL, query::Event::Started, false, (0, 1, -1), LEECHER),
+            (PARTIAL, query::Event::Started, true, (1, 0, -1), SEEDER),
+            (PARTIAL, query::Event::Completed, false, (0, 1, -1), LEECHER),
+            (PARTIAL, query::Event::Completed, true, (1, 0, -1), SEEDER),
+            (PARTIAL, query::Event::Paused, false, (0, 0, 0), PARTIAL),
+            (PARTIAL, query::Event::Paused, true, (1, 0, -1), SEEDER),
+            (PARTIAL, query::Event::Stopped, false, (0, 0, -1), NONE),
+            (PARTIAL, query::Event::Stopped, true, (0, 0, -1), NONE),
+            (BOTH, query::Event::Unknown, false, (-1, 0, 0), LEECHER),
+            (BOTH, query::Event::Unknown, true, (0, -1, 0), SEEDER),
+            (BOTH, query::Event::Started, false, (-1, 0, 0), LEECHER),
+            (BOTH, query::Event::Started, true, (0, -1, 0), SEEDER),
+            (BOTH, query::Event::Completed, false, (-1, 0, 0), LEECHER),
+            (BOTH, query::Event::Completed, true, (0, -1, 0), SEEDER),
+            (BOTH, query::Event::Paused, false, (-1, -1, 1), PARTIAL),
+            (BOTH, query::Event::Paused, true, (0, -1, 0), SEEDER),
+            (BOTH, query::Event::Stopped, false, (-1, -1, 0), NONE),
+            (BOTH, query::Event::Stopped, true, (-1, -1, 0), NONE),
+        ];
+
+        for (before, event, is_seeding, mods, after) in cases {
+            let case = format!("{:?} {:?} seeding: {}", before, event, is_seeding);
+            let completed = matches!(event, query::Event::Completed) && is_seeding;
+            let parsed = query::PeerInfo {
+                ip_port: Some(*b"AAAAAA"),
+                ip6_port: Some(*b"BBBBBBBBBBBBBBBBBB"),
+                info_hash: RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+                is_seeding,
+                event,
+                numwant: None,
+                uploaded: 0,
+                downloaded: 0,
+            };
+
+            let mut mutations = Vec::new();
+            assert_eq!(mods, record_announce(&mut mutations, &parsed, &before, 100), "{}", case);
+            assert_eq!((after, completed as i64), replay(&mutations, Family::V4, b"AAAAAA", before), "{}", case);
+
+            // The counts follow the sets
+            let count_mod = |role: Role| after.is(role) as i64 - before.counts_as(role) as i64;
+            assert_eq!(mods, (count_mod(Role::Seeder), count_mod(Role::Leecher), count_mod(Role::PartialSeed)), "{}", case);
+
+            // The secondary endpoint ends up the same, whatever it was in
+            for secondary_before in [NONE, SEEDER, LEECHER, PARTIAL, BOTH] {
+                assert_eq!(after, replay(&mutations, Family::V6, b"BBBBBBBBBBBBBBBBBB", secondary_before).0, "{}", case);
+            }
+            // And is marked as a mirror while it's in one
+            let marked = Mutation::MarkMirror { info_hash: parsed.info_hash, ip_port: b"BBBBBBBBBBBBBBBBBB".to_vec(), time_ms: 100 };
+            assert_eq!(after != NONE, mutations.contains(&marked), "{}", case);
+        }
+    }
+
+    #[test]
+    fn counts_former_mirrors() {
+        // An IPv6 peer that's in the leechers as a dual stack peer's mirror, announcing by itself
+        let parsed = query::PeerInfo {
+            ip_port: None,
+            ip6_port: Some(*b"BBBBBBBBBBBBBBBBBB"),
+            info_hash: RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+            is_seeding: false,
+            event: query::Event::Unknown,
+            numwant: None,
+            uploaded: 0,
+            downloaded: 0,
+        };
+
+        let mut mutations = Vec::new();
+        assert_eq!((0, 1, 0), record_announce(&mut mutations, &parsed, &PeerRole { is_mirror: true, ..LEECHER }, 100));
+        assert!(mutations.contains(&Mutation::UnmarkMirror { info_hash: parsed.info_hash, ip_port: b"BBBBBBBBBBBBBBBBBB".to_vec() }));
+        assert_eq!((LEECHER, 0), replay(&mutations, Family::V6, b"BBBBBBBBBBBBBBBBBB", LEECHER));
+    }
+
+    #[test]
+    fn plans_replies() {
+        assert_eq!(ReplyPlan { source: ReplySource::Fetch, cache: true }, plan_reply(0, 0, 0, CacheState::Miss));
+        assert_eq!(ReplyPlan { source: ReplySource::Fetch, cache: false }, plan_reply(1, -1, 0, CacheState::Miss));
+        assert_eq!(ReplyPlan { source: ReplySource::Cached { seed_count_mod: 0, leech_count_mod: 0, partial_count_mod: 0 }, cache: true }, plan_reply(0, 0, 0, CacheState::Hit));
+        assert_eq!(ReplyPlan { source: ReplySource::Cached { seed_count_mod: 0, leech_count_mod: -1, partial_count_mod: 1 }, cache: false }, plan_reply(0, -1, 1, CacheState::Hit));
+    }
+
+    /// A torrent the way the stores keep it, plus the reply cache
+    #[derive(Default)]
+    struct Torrent {
+        sets: HashSet<(Family, Role, Vec<u8>)>,
+        mirrors: HashSet<Vec<u8>>,
+        /// Seeders, leechers, partial seeds
+        counts: (i64, i64, i64),
+        downloaded: i64,
+        /// The counts in the cached reply, if there is one
+        cached: Option<(i64, i64, i64)>,
+    }
+
+    impl Torrent {
+        fn role(&self, endpoint: &Endpoint) -> PeerRole {
+            let is_in = |role: Role| self.sets.contains(&(endpoint.family, role, endpoint.ip_port.to_vec()));
+            let is_mirror = endpoint.family == Family::V6 && self.mirrors.contains(endpoint.ip_port);
+            return PeerRole { is_seeder: is_in(Role::Seeder), is_leecher: is_in(Role::Leecher), is_partial_seed: is_in(Role::PartialSeed), is_mirror };
+        }
+
+        fn announce(&mut self, parsed: &query::PeerInfo) -> AnnouncePlan {
+            let cache = match self.cached {
+                Some(_) => CacheState::Hit,
+                None => CacheState::Miss,
+            };
+            let plan = plan_announce(parsed, &self.role(&endpoints(parsed).0), cache, 100);
+
+            for mutation in plan.mutations.clone() {
+                match mutation {
+                    Mutation::UpsertPeer { family, role, ip_port, .. } => {
+                        self.sets.insert((family, role, ip_port));
+                    },
+                    Mutation::RemovePeer { family, role, ip_port, .. } => {
+                        self.sets.remove(&(family, role, ip_port));
+                    },
+                    Mutation::MarkMirror { ip_port, .. } => {
+                        self.mirrors.insert(ip_port);
+                    },
+                    Mutation::UnmarkMirror { ip_port, .. } => {
+                        self.mirrors.remove(&ip_port);
+                    },
+                    Mutation::UpdateCounters { seeders, leechers, partial_seeds, downloaded, .. } => {
+                        self.counts = (self.counts.0 + seeders, self.counts.1 + leechers, self.counts.2 + partial_seeds);
+                        self.downloaded += downloaded;
+                    },
+                    Mutation::InvalidateCache { .. } => self.cached = None,
+                    _ => (),
+                }
+            }
+
+            return plan;
+        }
+    }
+
+    // Two IPv4 peers, a dual stack one and an IPv6 one
+    fn peer(n: usize, event: u8, is_seeding: bool) -> query::PeerInfo {
+        let (ip_port, ip6_port) = match n {
+            0 => (Some(*b"AAAAAA"), None),
+            1 => (Some(*b"BBBBBB"), None),
+            2 => (Some(*b"CCCCCC"), Some(*b"CCCCCCCCCCCCCCCCCC")),
+            _ => (None, Some(*b"DDDDDDDDDDDDDDDDDD")),
+        };
+        let event = match event {
+            0 => query::Event::Unknown,
+            1 => query::Event::Started,
+            2 => query::Event::Completed,
+            3 => query::Event::Paused,
+            _ => query::Event::Stopped,
+        };
+
+        return query::PeerInfo { ip_port, ip6_port, info_hash: RawVal(*b"41AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"), is_seeding, event, numwant: None, uploaded: 0, downloaded: 0 };
+    }
+
+    proptest! {
+        #[test]
+        fn counters_follow_the_swarm(announces in prop::collection::vec((0..4usize, 0..5u8, any::<bool>()), 1..60)) {
+            let mut torrent = Torrent::default();
+            let mut completed = 0;
+
+            for (n, event, is_seeding) in announces {
+                let parsed = peer(n, event, is_seeding);
+                let cached = torrent.cached;
+                let plan = torrent.announce(&parsed);
+                let mods = (plan.seed_count_mod, plan.leech_count_mod, plan.partial_count_mod);
+
+                if matches!(parsed.event, query::Event::Completed) && is_seeding {
+                    completed += 1;
+                }
+
+                // The reply's counts are the swarm's, cached or fetched
+                match (plan.reply.source, cached) {
+                    (ReplySource::Cached { seed_count_mod, leech_count_mod, partial_count_mod }, Some(counts)) => {
+                        prop_assert_eq!((counts.0 + seed_count_mod, counts.1 + leech_count_mod, counts.2 + partial_count_mod), torrent.counts);
+                    },
+                    (ReplySource::Fetch, None) => (),
+                    (source, cached) => prop_assert!(false, "{:?} with {:?} cached", source, cached),
+                }
+
+                // Any change invalidates the cache, no change gets it cached again
+                prop_assert_eq!(mods != (0, 0, 0), plan.mutations.contains(&Mutation::InvalidateCache { info_hash: parsed.info_hash }));
+                prop_assert_eq!(mods == (0, 0, 0), plan.reply.cache);
+                if plan.reply.cache {
+                    torrent.cached = Some(torrent.counts);
+                }
+
+                // The counters are the primary endpoints in each set, and every peer is in one set at most
+                let mut counts = (0, 0, 0);
+                for n in 0..4 {
+                    let announced = peer(n, 0, false);
+                    let (primary, secondary) = endpoints(&announced);
+                    let role = torrent.role(&primary);
+
+                    prop_assert!(ROLES.iter().filter(|&&r| role.is(r)).count() <= 1);
+                    counts = (counts.0 + role.is_seeder as i64, counts.1 + role.is_leecher as i64, counts.2 + role.is_partial_seed as i64);
+
+                    // Only marked as a mirror while it's in a set
+                    if let Some(secondary) = secondary {
+                        let is_in = ROLES.iter().any(|&r| role.is(r));
+                        prop_assert_eq!(PeerRole { is_mirror: is_in, ..role }, torrent.role(&secondary));
+                    }
+                }
+
+                prop_assert_eq!((counts, completed), (torrent.counts, torrent.downloaded));
+            }
+        }
+    }
+}
@@ -0,0 +1,447 @@
+// UDP tracker protocol, as per BEP 15
+// https://www.bittorrent.org/beps/bep_0015.html
+//
+// Announces go through the same bookkeeping as the HTTP handler (`record_announce`),
+// so HTTP and UDP peers of a torrent share the same swarm, counts and cached replies.
+
+use actix_web::{rt::net::UdpSocket, web};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{byte_functions, metrics, query, rate_limit, store, swarm, AppState};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+const CONNECT_REQUEST_LEN: usize = 16;
+const ANNOUNCE_REQUEST_LEN: usize = 98;
+const SCRAPE_HEADER_LEN: usize = 16;
+
+// Clients may use a connection ID for up to two minutes
+const CONNECTION_ID_WINDOW_SECS: u64 = 120;
+
+/// Hands out connection IDs without keeping any state per client.
+/// The ID is a keyed hash of the client's address and the current time window,
+/// so we can check it on announce / scrape by just recomputing it.
+pub struct ConnectionIds {
+    key: RandomState,
+}
+
+impl ConnectionIds {
+    pub fn new() -> ConnectionIds {
+        return ConnectionIds { key: RandomState::new() };
+    }
+
+    fn make(&self, addr: &SocketAddr, window: u64) -> u64 {
+        let mut hasher = self.key.build_hasher();
+        addr.hash(&mut hasher);
+        window.hash(&mut hasher);
+        return hasher.finish();
+    }
+
+    pub fn generate(&self, addr: &SocketAddr, time_now_secs: u64) -> u64 {
+        return self.make(addr, time_now_secs / CONNECTION_ID_WINDOW_SECS);
+    }
+
+    /// Accept IDs from the current & previous window, so an ID handed out
+    /// just before the window rolls over is still good
+    pub fn is_valid(&self, addr: &SocketAddr, connection_id: u64, time_now_secs: u64) -> bool {
+        let window = time_now_secs / CONNECTION_ID_WINDOW_SECS;
+        return connection_id == self.make(addr, window) || connection_id == self.make(addr, window.saturating_sub(1));
+    }
+}
+
+/// Receive packets forever, handling each one in its own task
+pub async fn serve(socket: UdpSocket, data: web::Data<AppState>) {
+    let socket = Rc::new(socket);
+    let connection_ids = Rc::new(ConnectionIds::new());
+    let mut buf = [0u8; 2048];
+
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Err receiving UDP packet {}", e);
+                continue;
+            }
+        };
+
+        let packet = buf[..len].to_vec();
+        let socket = socket.clone();
+        let connection_ids = connection_ids.clone();
+        let data = data.clone();
+
+        actix_web::rt::spawn(async move {
+            if let Some(reply) = handle_packet(&packet, src, &connection_ids, &data).await {
+                if let Err(e) = socket.send_to(&reply, src).await {
+                    println!("Err sending UDP reply to {}: {}", src, e);
+                }
+            }
+        });
+    }
+}
+
+async fn handle_packet(packet: &[u8], src: SocketAddr, connection_ids: &ConnectionIds, data: &web::Data<AppState>) -> Option<Vec<u8>> {
+    // Too short to even have a transaction ID, ignore it
+    if packet.len() < 16 {
+        return None;
+    }
+
+    let connection_id = read_u64(packet, 0);
+    let action = read_u32(packet, 8);
+    let transaction_id = read_u32(packet, 12);
+    let time_now_secs = SystemTime::now().duration_since(UNIX_EPOCH).expect("fucked up").as_secs();
+
+    if action == ACTION_CONNECT {
+        if packet.len() < CONNECT_REQUEST_LEN || connection_id != PROTOCOL_ID {
+            return None;
+        }
+
+        return Some(connect_reply(transaction_id, connection_ids.generate(&src, time_now_secs)));
+    }
+
+    if !connection_ids.is_valid(&src, connection_id, time_now_secs) {
+        return Some(error_reply(transaction_id, "Invalid connection ID"));
+    }
+
+    match action {
+        ACTION_ANNOUNCE => {
+            let started = Instant::now();
+            let parsed = match parse_announce(&src, packet) {
+                Some(parsed) => parsed,
+                None => {
+                    data.metrics.observe_request("udp", "announce", metrics::Outcome::Invalid, started);
+                    return Some(error_reply(transaction_id, "Malformed announce"));
+                },
+            };
+
+            // No passkeys over UDP
+            if data.passkeys.is_some() {
+                data.metrics.observe_request("udp", "announce", metrics::Outcome::Unauthorized, started);
+                return Some(error_reply(transaction_id, "Private tracker, announce over HTTP"));
+            }
+
+            if let Err(reason) = data.access.check(&parsed.info_hash) {
+                data.metrics.observe_request("udp", "announce", metrics::Outcome::Denied, started);
+                return Some(error_reply(transaction_id, reason));
+            }
+
+            let (reply, outcome) = match announce(&parsed, data).await {
+                Ok(Ok((seeders_count, leechers_count, peers))) => {
+                    (announce_reply(transaction_id, seeders_count, leechers_count, data.settings.get().announce_interval, &peers), metrics::Outcome::Ok)
+                },
+                // BEP 15 errors are just a message, so that's where `retry in` goes
+                Ok(Err(early_ms)) => {
+                    let message = match rate_limit::retry_in(early_ms) {
+                        query::RetryIn::Minutes(minutes) => format!("Announcing too often, retry in {} min", minutes),
+                        query::RetryIn::Never => "Announcing too often".to_string(),
+                    };
+                    (error_reply(transaction_id, &message), metrics::Outcome::RateLimited)
+                },
+                Err(e) => {
+                    println!("Err during UDP announce {}", e);
+                    (error_reply(transaction_id, "Internal error"), metrics::Outcome::BackendError)
+                }
+            };
+
+            data.metrics.observe_request("udp", "announce", outcome, started);
+            Some(reply)
+        },
+        ACTION_SCRAPE => {
+            let started = Instant::now();
+            let info_hashes = parse_scrape(packet);
+
+            let (reply, outcome) = match scrape(&info_hashes, data).await {
+                Ok(stats) => (scrape_reply(transaction_id, &stats), metrics::Outcome::Ok),
+                Err(e) => {
+                    println!("Err during UDP scrape {}", e);
+                    (error_reply(transaction_id, "Internal error"), metrics::Outcome::BackendError)
+                }
+            };
+
+            data.metrics.observe_request("udp", "scrape", outcome, started);
+            Some(reply)
+        },
+        _ => Some(error_reply(transaction_id, "Unknown action")),
+    }
+}
+
+/// The counts & peers, or how early (ms) it came if too soon after the last one, see `rate_limit`
+async fn announce(parsed: &query::PeerInfo, data: &web::Data<AppState>) -> store::StoreResult<Result<(i64, i64, Vec<u8>), i64>> {
+    let time_now = SystemTime::now().duration_since(UNIX_EPOCH).expect("fucked up");
+    let time_now_ms: i64 = i64::try_from(time_now.as_millis()).expect("fucc");
+    let settings = data.settings.get();
+
+    let numwant = settings.numwant_buckets.bucket(parsed.numwant);
+
+    if let Some(early_ms) = data.rate_limiter.check(data.store.as_ref(), parsed, time_now_ms, settings.min_announce_interval as i64 * 1000).await? {
+        return Ok(Err(early_ms));
+    }
+
+    let (cached_peers, reply_mods) = crate::record_announce(data, parsed, numwant, &settings, time_now_ms, None).await?;
+
+    // BEP 15: peers of the same family as the announce (6 bytes for IPv4, 18 for IPv6),
+    // which is the primary one since UDP announces only ever have one address
+    let (primary, _) = swarm::endpoints(parsed);
+    let (seeders, leechers) = match primary.family {
+        store::Family::V4 => (&cached_peers.seeders, &cached_peers.leechers),
+        store::Family::V6 => (&cached_peers.seeders6, &cached_peers.leechers6),
+    };
+    let peers = query::pick_peers(seeders, leechers, primary.ip_port.len(), Some(primary.ip_port), parsed.is_done(), numwant);
+
+    return Ok(Ok((cached_peers.seeders_count + reply_mods.0, cached_peers.leechers_count + reply_mods.1, peers)));
+}
+
+/// The reply is positional, so the torrents we don't track get zeros rather than being left out
+async fn scrape(info_hashes: &[byte_functions::types::RawVal<40>], data: &AppState) -> store::StoreResult<Vec<store::TorrentStats>> {
+    let is_tracked: Vec<bool> = info_hashes.iter().map(|info_hash| data.access.check(info_hash).is_ok()).collect();
+    let tracked: Vec<_> = info_hashes.iter().zip(&is_tracked).filter(|(_, &is_tracked)| is_tracked).map(|(info_hash, _)| *info_hash).collect();
+    let mut stats = data.store.fetch_stats(&tracked).await?.into_iter();
+
+    return Ok(is_tracked.into_iter().map(|is_tracked| match is_tracked {
+        true => stats.next().expect("stats for each tracked torrent"),
+        false => store::TorrentStats { seeders: 0, leechers: 0, partial_seeds: 0, downloaded: 0 },
+    }).collect());
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> u16 {
+    return u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&packet[offset..offset + 4]);
+    return u32::from_be_bytes(bytes);
+}
+
+fn read_u64(packet: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&packet[offset..offset + 8]);
+    return u64::from_be_bytes(bytes);
+}
+
+fn read_info_hash(packet: &[u8], offset: usize) -> byte_functions::types::RawVal<40> {
+    let mut raw = [0u8; 20];
+    raw.copy_from_slice(&packet[offset..offset + 20]);
+    return byte_functions::types::RawVal(byte_functions::raw_to_hex_u8(&raw));
+}
+
+/// Parse an announce request into the same `PeerInfo` the HTTP handler uses.
+/// As with HTTP, we ignore the IP in the packet and use the source address.
+fn parse_announce(src: &SocketAddr, packet: &[u8]) -> Option<query::PeerInfo> {
+    if packet.len() < ANNOUNCE_REQUEST_LEN {
+        return None;
+    }
+
+    let left = read_u64(packet, 64);
+
+    // -1 means the default
+    let numwant = u32::try_from(read_u32(packet, 92) as i32).ok();
+
+    let event = match read_u32(packet, 80) {
+        1 => query::Event::Completed,
+        2 => query::Event::Started,
+        3 => query::Event::Stopped,
+        // Not in BEP 15, but what libtorrent sends for BEP 21
+        4 => query::Event::Paused,
+        _ => query::Event::Unknown,
+    };
+
+    let port = read_u16(packet, 96);
+    let (ip_port, ip6_port) = match src.ip().to_canonical() {
+        IpAddr::V4(v4_addr) => (Some(byte_functions::ip_str_port_u16_to_bytes(&v4_addr, port)), None),
+        IpAddr::V6(v6_addr) => (None, Some(byte_functions::ip6_port_u16_to_bytes(&v6_addr, port))),
+    };
+
+    return Some(query::PeerInfo {
+        ip_port,
+        ip6_port,
+        info_hash: read_info_hash(packet, 16),
+        is_seeding: left == 0,
+        event,
+        numwant,
+        uploaded: read_u64(packet, 72),
+        downloaded: read_u64(packet, 56),
+    });
+}
+
+fn parse_scrape(packet: &[u8]) -> Vec<byte_functions::types::RawVal<40>> {
+    return packet[SCRAPE_HEADER_LEN..]
+    .chunks_exact(20)
+    .take(swarm::MAX_SCRAPE_TORRENTS)
+    .map(|chunk| {
+        let mut raw = [0u8; 20];
+        raw.copy_from_slice(chunk);
+        byte_functions::types::RawVal(byte_functions::raw_to_hex_u8(&raw))
+    })
+    .collect();
+}
+
+fn reply_header(action: u32, transaction_id: u32, capacity: usize) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(8 + capacity);
+    reply.extend_from_slice(&action.to_be_bytes());
+    reply.extend_from_slice(&transaction_id.to_be_bytes());
+    return reply;
+}
+
+fn connect_reply(transaction_id: u32, connection_id: u64) -> Vec<u8> {
+    let mut reply = reply_header(ACTION_CONNECT, transaction_id, 8);
+    reply.extend_from_slice(&connection_id.to_be_bytes());
+    return reply;
+}
+
+fn announce_reply(transaction_id: u32, seeders_count: i64, leechers_count: i64, interval: u32, peers: &[u8]) -> Vec<u8> {
+    let mut reply = reply_header(ACTION_ANNOUNCE, transaction_id, 12 + peers.len());
+    reply.extend_from_slice(&interval.to_be_bytes());
+    reply.extend_from_slice(&(leechers_count.max(0) as u32).to_be_bytes());
+    reply.extend_from_slice(&(seeders_count.max(0) as u32).to_be_bytes());
+    reply.extend_from_slice(peers);
+    return reply;
+}
+
+fn scrape_reply(transaction_id: u32, stats: &[store::TorrentStats]) -> Vec<u8> {
+    let mut reply = reply_header(ACTION_SCRAPE, transaction_id, stats.len() * 12);
+
+    // No field for downloaders, so partial seeds are counted as leechers
+    for torrent in stats {
+        reply.extend_from_slice(&(torrent.seeders as u32).to_be_bytes());
+        reply.extend_from_slice(&(torrent.downloaded as u32).to_be_bytes());
+        reply.extend_from_slice(&((torrent.leechers + torrent.partial_seeds) as u32).to_be_bytes());
+    }
+
+    return reply;
+}
+
+fn error_reply(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = reply_header(ACTION_ERROR, transaction_id, message.len());
+    reply.extend_from_slice(message.as_bytes());
+    return reply;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce_packet(left: u64, event: u32, numwant: i32, port: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&1234u64.to_be_bytes()); // connection_id
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&5678u32.to_be_bytes()); // transaction_id
+        packet.extend_from_slice(&[0x41; 20]); // info_hash
+        packet.extend_from_slice(&[0x2d; 20]); // peer_id
+        packet.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+        packet.extend_from_slice(&left.to_be_bytes());
+        packet.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+        packet.extend_from_slice(&event.to_be_bytes());
+        packet.extend_from_slice(&0u32.to_be_bytes()); // ip
+        packet.extend_from_slice(&0u32.to_be_bytes()); // key
+        packet.extend_from_slice(&numwant.to_be_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        return packet;
+    }
+
+    #[test]
+    fn can_parse_announce() {
+        let src: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let packet = announce_packet(0, 1, -1, 3333);
+        assert_eq!(ANNOUNCE_REQUEST_LEN, packet.len());
+
+        let parsed = parse_announce(&src, &packet).unwrap();
+        assert_eq!(Some([127, 0, 0, 1, 13, 5]), parsed.ip_port);
+        assert_eq!(None, parsed.ip6_port);
+        assert_eq!(*b"4141414141414141414141414141414141414141", parsed.info_hash.0);
+        assert!(parsed.is_seeding);
+        assert!(matches!(parsed.event, query::Event::Completed));
+        assert_eq!(None, parsed.numwant);
+
+        let parsed = parse_announce(&src, &announce_packet(100, 3, 20, 3333)).unwrap();
+        assert!(!parsed.is_seeding);
+        assert_eq!(Some(20), parsed.numwant);
+        assert!(matches!(parsed.event, query::Event::Stopped));
+
+        assert!(matches!(parse_announce(&src, &announce_packet(100, 2, -1, 3333)).unwrap().event, query::Event::Started));
+        let parsed = parse_announce(&src, &announce_packet(100, 4, -1, 3333)).unwrap();
+        assert!(matches!(parsed.event, query::Event::Paused));
+        assert!(parsed.is_partial_seed());
+
+        assert!(parse_announce(&src, &packet[..90]).is_none());
+
+        let src6: SocketAddr = "[::1]:1000".parse().unwrap();
+        let parsed = parse_announce(&src6, &packet).unwrap();
+        assert_eq!(None, parsed.ip_port);
+        assert_eq!(Some([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 13, 5]), parsed.ip6_port);
+    }
+
+    #[test]
+    fn can_parse_scrape() {
+        let mut packet = vec![0u8; SCRAPE_HEADER_LEN];
+        packet.extend_from_slice(&[0x41; 20]);
+        packet.extend_from_slice(&[0xab; 20]);
+        packet.extend_from_slice(&[0xff; 5]); // Trailing junk is ignored
+
+        let info_hashes = parse_scrape(&packet);
+        assert_eq!(2, info_hashes.len());
+        assert_eq!(*b"abababababababababababababababababababab", info_hashes[1].0);
+    }
+
+    #[test]
+    fn connection_ids_expire() {
+        let connection_ids = ConnectionIds::new();
+        let addr: SocketAddr = "127.0.0.1:3333".parse().unwrap();
+        let other_addr: SocketAddr = "127.0.0.1:3334".parse().unwrap();
+
+        let connection_id = connection_ids.generate(&addr, 1000);
+        assert!(connection_ids.is_valid(&addr, connection_id, 1000));
+        assert!(connection_ids.is_valid(&addr, connection_id, 1000 + CONNECTION_ID_WINDOW_SECS));
+        assert!(!connection_ids.is_valid(&addr, connection_id, 1000 + 2 * CONNECTION_ID_WINDOW_SECS));
+        assert!(!connection_ids.is_valid(&other_addr, connection_id, 1000));
+    }
+
+    #[test]
+    fn replies_are_well_formed() {
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 9], connect_reply(7, 9));
+
+        let reply = announce_reply(7, 2, 1, 1800, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(26, reply.len());
+        assert_eq!(ACTION_ANNOUNCE, read_u32(&reply, 0));
+        assert_eq!(1800, read_u32(&reply, 8));
+        assert_eq!(1, read_u32(&reply, 12)); // leechers
+        assert_eq!(2, read_u32(&reply, 16)); // seeders
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], reply[20..].to_vec());
+
+        let reply = scrape_reply(7, &[store::TorrentStats { seeders: 3, leechers: 4, partial_seeds: 1, downloaded: 4 }]);
+        assert_eq!(vec![3, 4, 5], vec![read_u32(&reply, 8), read_u32(&reply, 12), read_u32(&reply, 16)]);
+
+        let reply = error_reply(7, "nope");
+        assert_eq!(ACTION_ERROR, read_u32(&reply, 0));
+        assert_eq!(b"nope".to_vec(), reply[8..].to_vec());
+    }
+
+    #[actix_web::test]
+    async fn throttled_announces_get_an_error_with_retry_in() {
+        let data = crate::tests::app_state(rate_limit::Mode::Memory);
+        let connection_ids = ConnectionIds::new();
+        let src: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+
+        let mut packet = announce_packet(100, 0, -1, 3333);
+        let time_now_secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        packet[..8].copy_from_slice(&connection_ids.generate(&src, time_now_secs).to_be_bytes());
+
+        let reply = handle_packet(&packet, src, &connection_ids, &data).await.unwrap();
+        assert_eq!(ACTION_ANNOUNCE, read_u32(&reply, 0));
+        assert_eq!((1, 0), (read_u32(&reply, 12), read_u32(&reply, 16))); // Counts itself
+
+        // Same again right away, a min interval (30 minutes by default) too early
+        let reply = handle_packet(&packet, src, &connection_ids, &data).await.unwrap();
+        assert_eq!(ACTION_ERROR, read_u32(&reply, 0));
+        assert_eq!(5678, read_u32(&reply, 4));
+        assert_eq!(b"Announcing too often, retry in 30 min".to_vec(), reply[8..].to_vec());
+    }
+}